@@ -1,48 +1,218 @@
-use std::io::Write;
-
 use rand::Rng;
-use ring::error::Unspecified;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use types::{KdfParams, KeyDerivation, PublicKeyInfo, SignatureAlgorithm, SignedTreeHead};
 
 fn generate_random_nonce() -> [u8; 12] {
     let mut rng = rand::thread_rng();
     rng.gen::<[u8; 12]>()
 }
 
-// TODO KEY NOT PASSWORD?
+/// Key the contents and names of files are encrypted with
+pub type Key = aes_gcm_siv::Key<aes_gcm_siv::aead::consts::U32>;
+
+static KDF_MEMORY_VAR: &str = "KRYPTO_KDF_MEMORY";
+static KDF_TIME_VAR: &str = "KRYPTO_KDF_TIME";
+static KDF_LANES_VAR: &str = "KRYPTO_KDF_LANES";
+
+/// Version of the subkey derivation new accounts use, only 1 exists so far
+const SUBKEY_VERSION: u32 = 1;
+
+/// Argon2id costs the client agrees to derive keys with. The parameters come
+/// from the server, which could otherwise make the key cheap to brute force
+/// or make every login run out of memory.
+const MIN_MEM_COST: u32 = 19 * 1024; // KiB
+const MAX_MEM_COST: u32 = 1024 * 1024;
+const MIN_TIME_COST: u32 = 2;
+const MAX_TIME_COST: u32 = 16;
+const MAX_LANES: u32 = 16;
+const MIN_SALT_LEN: usize = 16;
+
+/// Argon2id cost for new accounts, 19 MiB and 2 passes unless overridden
+/// through the environment. Overrides are clamped to what the client
+/// accepts when logging in.
+pub fn kdf_params() -> KdfParams {
+    fn var(name: &str, default: u32, min: u32, max: u32) -> u32 {
+        std::env::var(name)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(default)
+            .clamp(min, max)
+    }
+
+    KdfParams {
+        version: SUBKEY_VERSION,
+        mem_cost: var(KDF_MEMORY_VAR, MIN_MEM_COST, MIN_MEM_COST, MAX_MEM_COST),
+        time_cost: var(KDF_TIME_VAR, MIN_TIME_COST, MIN_TIME_COST, MAX_TIME_COST),
+        lanes: var(KDF_LANES_VAR, 1, 1, MAX_LANES),
+    }
+}
+
+/// Refuses a salt or costs outside the bounds above, or a subkey derivation
+/// this client doesn't know
+pub fn check_derivation(salt: &[u8], params: &KdfParams) -> Result<(), CryptoError> {
+    if params.version != SUBKEY_VERSION
+        || salt.len() < MIN_SALT_LEN
+        || !(MIN_MEM_COST..=MAX_MEM_COST).contains(&params.mem_cost)
+        || !(MIN_TIME_COST..=MAX_TIME_COST).contains(&params.time_cost)
+        || !(1..=MAX_LANES).contains(&params.lanes)
+    {
+        return Err(CryptoError::BadKdfParams);
+    }
+    Ok(())
+}
+
+/// Random salt for a new account, kept by the server
+pub fn generate_salt() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    rng.gen::<[u8; 16]>().to_vec()
+}
+
+/// Derives the master key from the password with Argon2id. It is never used
+/// directly, only to derive a subkey for each purpose.
+pub fn derive_key(password: &str, salt: &[u8], params: &KdfParams) -> Result<Key, CryptoError> {
+    use argon2::{Config, ThreadMode, Variant, Version};
+
+    check_derivation(salt, params)?;
+    let config = Config {
+        ad: &[],
+        hash_length: 32,
+        lanes: params.lanes,
+        mem_cost: params.mem_cost,
+        secret: &[],
+        thread_mode: ThreadMode::Sequential,
+        time_cost: params.time_cost,
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+    };
+    let hash: [u8; 32] = argon2::hash_raw(password.as_bytes(), salt, &config)
+        .map_err(|_| CryptoError::KeyDerivation)?
+        .try_into()
+        .map_err(|_| CryptoError::KeyDerivation)?;
+    Ok(Key::from(hash))
+}
+
+/// What a subkey is used for. Every purpose gets its own key, so a key
+/// leaking or being misused for one purpose doesn't affect the others.
+#[derive(Clone, Copy)]
+pub enum Purpose {
+    Contents,
+    Names,
+    NameLookup,
+    PrivateKey,
+    #[allow(dead_code)] // reserved for encrypted metadata such as timestamps
+    Metadata,
+}
+
+impl Purpose {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Purpose::Contents => b"contents",
+            Purpose::Names => b"names",
+            Purpose::NameLookup => b"name lookup",
+            Purpose::PrivateKey => b"private key",
+            Purpose::Metadata => b"metadata",
+        }
+    }
+}
+
+/// Derives the subkey for a purpose from the master key with HKDF-SHA256.
+/// The version is part of the HKDF info, any version but 1 is refused until
+/// a new derivation gets added.
+pub fn derive_subkey(master: &Key, version: u32, purpose: Purpose) -> Result<Key, CryptoError> {
+    use ring::hkdf;
+
+    if version != SUBKEY_VERSION {
+        return Err(CryptoError::KeyDerivation);
+    }
+
+    let version = version.to_be_bytes();
+    let info = [&b"krypto subkey"[..], &version, purpose.label()];
+    let mut subkey = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(master)
+        .expand(&info, hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut subkey))
+        .map_err(|_| CryptoError::KeyDerivation)?;
+    Ok(Key::from(subkey))
+}
+
+/// The logged in user's password along with the salt and cost keys are
+/// derived from it with. Argon2id is slow on purpose, so the master key for
+/// each set of parameters is only derived once.
+pub struct KeyChain {
+    password: String,
+    derivation: KeyDerivation,
+    masters: HashMap<KdfParams, Key>,
+}
+
+impl KeyChain {
+    pub fn new(password: String, derivation: KeyDerivation) -> Self {
+        Self {
+            password,
+            derivation,
+            masters: HashMap::new(),
+        }
+    }
 
-fn generate_key(password: String)-> Result<aes_gcm_siv::Key<aes_gcm_siv::aead::consts::U32>, String> {
-    let mut pass_padded: Vec<u8> = vec![0; 32];
-    pass_padded.as_mut_slice().write(password.as_bytes()).map_err(|e| format!("write error {}", e))?;
+    /// Parameters files get encrypted with
+    pub fn params(&self) -> KdfParams {
+        self.derivation.params
+    }
 
-    Ok(*aes_gcm_siv::Key::from_slice(&pass_padded))
+    /// The subkey for a purpose, derived with the parameters a file was
+    /// encrypted with. Files encrypted before the account's parameters
+    /// changed keep working, as long as their parameters are within bounds.
+    pub fn key(&mut self, params: &KdfParams, purpose: Purpose) -> Result<Key, CryptoError> {
+        let master = match self.masters.get(params) {
+            Some(master) => *master,
+            None => {
+                let master = derive_key(&self.password, &self.derivation.salt, params)?;
+                *self.masters.entry(*params).or_insert(master)
+            }
+        };
+        derive_subkey(&master, params.version, purpose)
+    }
+
+    /// Hex encoded HMAC-SHA256 of a file name, which the server looks files
+    /// up by. Keyed with the current lookup key so the same name gives a
+    /// different hash for every account.
+    pub fn name_hash(&mut self, file_name: &str) -> Result<String, CryptoError> {
+        use ring::hmac;
+
+        let lookup = self.key(&self.params(), Purpose::NameLookup)?;
+        let tag = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, &lookup),
+            file_name.as_bytes(),
+        );
+        Ok(tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect())
+    }
 }
 
-/** password in plain text, returns decrypted message */
+/** returns decrypted message */
 pub fn decrypt_bytes(
     bytes: Vec<u8>,
-    password: String,
+    key: &Key,
     nonce_bytes: [u8; 12],
-) -> Result<Vec<u8>, String> {
+) -> Result<Vec<u8>, CryptoError> {
     use aes_gcm_siv::aead::{Aead, NewAead};
     use aes_gcm_siv::{Aes256GcmSiv, Nonce}; // Or `Aes128GcmSiv`
 
-    let key = generate_key(password)?;
-    let cipher = Aes256GcmSiv::new(&key);
+    let cipher = Aes256GcmSiv::new(key);
 
     let nonce = &Nonce::from(nonce_bytes);
 
-    Ok(cipher
+    cipher
         .decrypt(nonce, bytes.as_ref())
-        .map_err(|_| String::from("decryption failure!"))?) // NOTE: handle this error to avoid panics!
+        .map_err(|_| CryptoError::Decryption)
 }
 
-/** password in plain text, returns nonce_bytes and encrypted message */
-pub fn encrypt_bytes(bytes: Vec<u8>, password: String) -> Result<([u8; 12], Vec<u8>), String> {
+/** returns nonce_bytes and encrypted message */
+pub fn encrypt_bytes(bytes: Vec<u8>, key: &Key) -> Result<([u8; 12], Vec<u8>), CryptoError> {
     use aes_gcm_siv::aead::{Aead, NewAead};
     use aes_gcm_siv::{Aes256GcmSiv, Nonce}; // Or `Aes128GcmSiv`
 
-    let key = generate_key(password)?;
-    let cipher = Aes256GcmSiv::new(&key);
+    let cipher = Aes256GcmSiv::new(key);
 
     let nonce_bytes = generate_random_nonce();
 
@@ -50,66 +220,569 @@ pub fn encrypt_bytes(bytes: Vec<u8>, password: String) -> Result<([u8; 12], Vec<
 
     let ciphertext = cipher
         .encrypt(nonce, bytes.as_ref())
-        .map_err(|_| String::from("encryption failure!"))?; // NOTE: handle this error to avoid panics!
+        .map_err(|_| CryptoError::Encryption)?;
 
     Ok((nonce_bytes, ciphertext))
 }
 
-pub fn hash_password(salt: String, password: String) -> String {
+/// Hashes the password before it is sent, so the server never learns the
+/// password the encryption key is derived from
+pub fn hash_password(salt: String, password: String) -> Result<String, CryptoError> {
     use argon2::Config;
 
-    let password = password.as_bytes();
-    let salt = salt.as_bytes();
     let config = Config::default();
-    let hash = argon2::hash_encoded(password, salt, &config).unwrap();
-    let matches = argon2::verify_encoded(&hash, password).unwrap();
-    assert!(matches);
+    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &config)
+        .map_err(|_| CryptoError::Hashing)
+}
+
+/// A private key files get signed with. The kind of key is read from the
+/// PKCS#8 document, ring refuses documents for any other algorithm.
+pub enum SigningKey {
+    Rsa(ring::signature::RsaKeyPair),
+    Ed25519(ring::signature::Ed25519KeyPair),
+    EcdsaP256(ring::signature::EcdsaKeyPair),
+}
+
+impl SigningKey {
+    pub fn from_pkcs8(key_data: &[u8]) -> Result<Self, CryptoError> {
+        use ring::signature::{
+            EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+        };
+
+        if let Ok(key_pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(key_data) {
+            return Ok(SigningKey::Ed25519(key_pair));
+        }
+        if let Ok(key_pair) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key_data) {
+            return Ok(SigningKey::EcdsaP256(key_pair));
+        }
+        RsaKeyPair::from_pkcs8(key_data)
+            .map(SigningKey::Rsa)
+            .map_err(|_| CryptoError::BadPrivateKey)
+    }
 
-    hash
+    /// SHA256 of the public key, short enough to compare by eye
+    pub fn fingerprint(&self) -> String {
+        crate::state::to_hex(&self.public_key_info().fingerprint())
+    }
+
+    pub fn public_key_info(&self) -> PublicKeyInfo {
+        PublicKeyInfo {
+            algorithm: self.algorithm(),
+            public_key: self.public_key(),
+        }
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self {
+            SigningKey::Rsa(_) => SignatureAlgorithm::RsaPkcs1Sha256,
+            SigningKey::Ed25519(_) => SignatureAlgorithm::Ed25519,
+            SigningKey::EcdsaP256(_) => SignatureAlgorithm::EcdsaP256Sha256,
+        }
+    }
+
+    /// Public key files signed with this key verify against, DER encoded
+    /// for RSA, raw for Ed25519 and an uncompressed point for ECDSA
+    pub fn public_key(&self) -> Vec<u8> {
+        use ring::signature::KeyPair;
+
+        match self {
+            SigningKey::Rsa(key_pair) => key_pair.public_key().as_ref().to_vec(),
+            SigningKey::Ed25519(key_pair) => key_pair.public_key().as_ref().to_vec(),
+            SigningKey::EcdsaP256(key_pair) => key_pair.public_key().as_ref().to_vec(),
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let rng = ring::rand::SystemRandom::new();
+        match self {
+            SigningKey::Rsa(key_pair) => {
+                let mut signature = vec![0; key_pair.public_modulus_len()];
+                key_pair
+                    .sign(
+                        &ring::signature::RSA_PKCS1_SHA256,
+                        &rng,
+                        message,
+                        &mut signature,
+                    )
+                    .map_err(|_| CryptoError::OOM)?;
+                Ok(signature)
+            }
+            SigningKey::Ed25519(key_pair) => Ok(key_pair.sign(message).as_ref().to_vec()),
+            SigningKey::EcdsaP256(key_pair) => key_pair
+                .sign(&rng, message)
+                .map(|signature| signature.as_ref().to_vec())
+                .map_err(|_| CryptoError::OOM),
+        }
+    }
 }
 
-pub fn get_key_pair(path: &std::path::Path) -> Result<ring::signature::RsaKeyPair, CryptoError> {
-    let key_data = read_file(path)?;
-    Ok(ring::signature::RsaKeyPair::from_pkcs8(&key_data)
-        .map_err(|_| CryptoError::BadPrivateKey)?)
+/// A private key encrypted with a subkey of an Argon2id key derived from a
+/// passphrase, the way signing keys are kept on disk
+#[derive(Serialize, Deserialize)]
+pub struct WrappedKey {
+    salt: Vec<u8>,
+    params: KdfParams,
+    nonce: [u8; 12],
+    key: Vec<u8>,
 }
 
-pub fn sign_file(
-    file_data: &Vec<u8>,
-    file_name: &[u8],
-    key_pair: &ring::signature::RsaKeyPair,
-) -> Result<Vec<u8>, CryptoError> {
-    let mut full_file = file_data.to_vec();
-    full_file.append(&mut file_name.to_vec());
+impl WrappedKey {
+    pub fn wrap(pkcs8: &[u8], passphrase: &str) -> Result<Self, CryptoError> {
+        let salt = generate_salt();
+        let params = kdf_params();
+        let wrapping_key = Self::wrapping_key(passphrase, &salt, &params)?;
+        let (nonce, key) = encrypt_bytes(pkcs8.to_vec(), &wrapping_key)?;
+        Ok(Self {
+            salt,
+            params,
+            nonce,
+            key,
+        })
+    }
 
-    let rng = ring::rand::SystemRandom::new();
-    let mut signature = vec![0; key_pair.public_modulus_len()];
-    key_pair
-        .sign(
-            &ring::signature::RSA_PKCS1_SHA256,
-            &rng,
-            &full_file,
-            &mut signature,
-        )
-        .map_err(|_| CryptoError::OOM)?;
+    /// Returns the PKCS#8 document, failing with `Decryption` if the
+    /// passphrase is wrong
+    pub fn unwrap(&self, passphrase: &str) -> Result<Vec<u8>, CryptoError> {
+        let wrapping_key = Self::wrapping_key(passphrase, &self.salt, &self.params)?;
+        decrypt_bytes(self.key.clone(), &wrapping_key, self.nonce)
+    }
 
-    Ok(signature)
+    fn wrapping_key(passphrase: &str, salt: &[u8], params: &KdfParams) -> Result<Key, CryptoError> {
+        let master = derive_key(passphrase, salt, params)?;
+        derive_subkey(&master, params.version, Purpose::PrivateKey)
+    }
+}
+
+/// Contents of a key file, which holds a bare PKCS#8 document if it was
+/// written before keys were wrapped
+pub enum KeyFile {
+    Plain(Vec<u8>),
+    Wrapped(WrappedKey),
+}
+
+pub fn read_key_file(path: &std::path::Path) -> Result<KeyFile, CryptoError> {
+    let data = read_file(path)?;
+    Ok(match serde_json::from_slice(&data) {
+        Ok(wrapped) => KeyFile::Wrapped(wrapped),
+        Err(_) => KeyFile::Plain(data),
+    })
+}
+
+/// Writes a wrapped key readable by the owner only, replacing any key
+/// already at `path` only once the new one is completely written
+pub fn write_key_file(path: &std::path::Path, wrapped: &WrappedKey) -> Result<(), CryptoError> {
+    use std::io::Write;
+
+    let data = serde_json::to_vec(wrapped).map_err(|_| CryptoError::Encryption)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(CryptoError::IO)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(CryptoError::IO(e)),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // other platforms don't have permission bits, the file keeps the
+    // defaults of its directory there
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(&data))
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(CryptoError::IO)
+}
+
+/// Generates a new Ed25519 key and writes it to `path` wrapped with the
+/// passphrase
+pub fn generate_key_pair(
+    path: &std::path::Path,
+    passphrase: &str,
+) -> Result<SigningKey, CryptoError> {
+    let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+        .map_err(|_| CryptoError::BadPrivateKey)?;
+    write_key_file(path, &WrappedKey::wrap(pkcs8.as_ref(), passphrase)?)?;
+    SigningKey::from_pkcs8(pkcs8.as_ref())
+}
+
+/// Where the public key of a key file is written, next to the key itself
+pub fn public_key_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".pub");
+    path.into()
+}
+
+/// Writes a public key for other devices to import, so they can verify
+/// files signed with the key without holding the private key
+pub fn write_public_key(path: &std::path::Path, key: &PublicKeyInfo) -> Result<(), CryptoError> {
+    let data = serde_json::to_vec(key).map_err(|_| CryptoError::BadPublicKey)?;
+    std::fs::write(path, data).map_err(CryptoError::IO)
+}
+
+pub fn read_public_key(path: &std::path::Path) -> Result<PublicKeyInfo, CryptoError> {
+    let key: PublicKeyInfo =
+        serde_json::from_slice(&read_file(path)?).map_err(|_| CryptoError::BadPublicKey)?;
+    if key.public_key.is_empty() {
+        return Err(CryptoError::BadPublicKey);
+    }
+    Ok(key)
+}
+
+/// Everything a file signature covers, so a signature can't be moved onto a
+/// file with another name, owner or an older version
+pub struct SignedFile<'a> {
+    pub owner: &'a str,
+    pub name: &'a str,
+    pub name_hash: &'a str,
+    pub nonce: &'a [u8; 12],
+    pub version: u64,
+    pub contents: &'a [u8],
+}
+
+impl SignedFile<'_> {
+    /// The bytes that get signed, every variable length field is length
+    /// prefixed so no two files produce the same input
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut data = b"krypto file signature v1".to_vec();
+        for field in [
+            self.owner.as_bytes(),
+            self.name.as_bytes(),
+            self.name_hash.as_bytes(),
+            self.nonce,
+            self.contents,
+        ] {
+            data.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            data.extend_from_slice(field);
+        }
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data
+    }
+}
+
+pub fn sign_file(file: &SignedFile, key_pair: &SigningKey) -> Result<Vec<u8>, CryptoError> {
+    key_pair.sign(&file.signed_bytes())
+}
+
+/// Checks a signature made by `sign_file` against the signer's public key,
+/// so files can be verified without the private key. The algorithm the file
+/// claims has to be the one of the key.
+pub fn verify_file(
+    file: &SignedFile,
+    algorithm: SignatureAlgorithm,
+    public_key: &PublicKeyInfo,
+    signature: &[u8],
+) -> Result<(), CryptoError> {
+    use ring::signature::{
+        VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, ED25519, RSA_PKCS1_2048_8192_SHA256,
+    };
+
+    if algorithm != public_key.algorithm {
+        return Err(CryptoError::BadSignature);
+    }
+    let algorithm: &dyn VerificationAlgorithm = match algorithm {
+        SignatureAlgorithm::RsaPkcs1Sha256 => &RSA_PKCS1_2048_8192_SHA256,
+        SignatureAlgorithm::Ed25519 => &ED25519,
+        SignatureAlgorithm::EcdsaP256Sha256 => &ECDSA_P256_SHA256_ASN1,
+    };
+    ring::signature::UnparsedPublicKey::new(algorithm, &public_key.public_key)
+        .verify(&file.signed_bytes(), signature)
+        .map_err(|_| CryptoError::BadSignature)
+}
+
+/// Checks that a tree head was signed with the server's Ed25519 key
+pub fn verify_tree_head(public_key: &[u8], head: &SignedTreeHead) -> Result<(), CryptoError> {
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(&head.head.signed_bytes(), &head.signature)
+        .map_err(|_| CryptoError::BadSignature)
 }
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CryptoError {
     IO(std::io::Error),
     BadPrivateKey,
+    BadPublicKey,
+    KeyDerivation,
+    BadKdfParams,
+    Hashing,
     OOM,
     BadSignature,
+    Encryption,
+    Decryption,
 }
 
 fn read_file(path: &std::path::Path) -> Result<Vec<u8>, CryptoError> {
     use std::io::Read;
 
-    let mut file = std::fs::File::open(path).map_err(|e| CryptoError::IO(e))?;
+    let mut file = std::fs::File::open(path).map_err(CryptoError::IO)?;
     let mut contents: Vec<u8> = Vec::new();
-    file.read_to_end(&mut contents)
-        .map_err(|e| CryptoError::IO(e))?;
+    file.read_to_end(&mut contents).map_err(CryptoError::IO)?;
     Ok(contents)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subkeys_differ_by_purpose() {
+        let master = Key::from([7u8; 32]);
+        let purposes = [
+            Purpose::Contents,
+            Purpose::Names,
+            Purpose::NameLookup,
+            Purpose::PrivateKey,
+            Purpose::Metadata,
+        ];
+        let subkeys = purposes
+            .iter()
+            .map(|purpose| derive_subkey(&master, SUBKEY_VERSION, *purpose).unwrap())
+            .collect::<Vec<_>>();
+
+        for (i, subkey) in subkeys.iter().enumerate() {
+            assert_ne!(*subkey, master);
+            assert!(subkeys[i + 1..].iter().all(|other| other != subkey));
+        }
+    }
+
+    #[test]
+    fn subkeys_depend_only_on_the_master_key() {
+        let master = Key::from([7u8; 32]);
+        assert_eq!(
+            derive_subkey(&master, SUBKEY_VERSION, Purpose::Contents).unwrap(),
+            derive_subkey(&master, SUBKEY_VERSION, Purpose::Contents).unwrap()
+        );
+        assert_ne!(
+            derive_subkey(&master, SUBKEY_VERSION, Purpose::Contents).unwrap(),
+            derive_subkey(&Key::from([8u8; 32]), SUBKEY_VERSION, Purpose::Contents).unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_subkey_versions_are_refused() {
+        let master = Key::from([7u8; 32]);
+        assert!(matches!(
+            derive_subkey(&master, SUBKEY_VERSION + 1, Purpose::Contents),
+            Err(CryptoError::KeyDerivation)
+        ));
+    }
+
+    /// One key of every kind files can be signed with
+    fn signing_keys() -> Vec<SigningKey> {
+        use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+        let rng = ring::rand::SystemRandom::new();
+        let ed25519 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let ecdsa = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        // ring can't generate RSA keys, the one from before keygen existed is used
+        let rsa = include_bytes!("../test-rsa-key.pk8");
+        [ed25519.as_ref(), ecdsa.as_ref(), &rsa[..]]
+            .iter()
+            .map(|pkcs8| SigningKey::from_pkcs8(pkcs8).unwrap())
+            .collect()
+    }
+
+    fn signed_file<'a>(name: &'a str, contents: &'a [u8]) -> SignedFile<'a> {
+        SignedFile {
+            owner: "alice",
+            name,
+            name_hash: "name hash",
+            nonce: &[1; 12],
+            version: 1,
+            contents,
+        }
+    }
+
+    #[test]
+    fn signatures_verify_for_every_algorithm() {
+        let keys = signing_keys();
+        let algorithms = keys.iter().map(|key| key.algorithm()).collect::<Vec<_>>();
+        assert_eq!(
+            algorithms,
+            vec![
+                SignatureAlgorithm::Ed25519,
+                SignatureAlgorithm::EcdsaP256Sha256,
+                SignatureAlgorithm::RsaPkcs1Sha256
+            ]
+        );
+
+        let file = signed_file("notes.txt", b"contents");
+        for key in &keys {
+            let signature = sign_file(&file, key).unwrap();
+            verify_file(&file, key.algorithm(), &key.public_key_info(), &signature).unwrap();
+        }
+    }
+
+    #[test]
+    fn signatures_dont_verify_with_other_keys() {
+        let keys = signing_keys();
+        let file = signed_file("notes.txt", b"contents");
+        for key in &keys {
+            let signature = sign_file(&file, key).unwrap();
+            for other in keys.iter().filter(|x| x.algorithm() != key.algorithm()) {
+                // claiming another algorithm than the key's is refused outright
+                assert!(
+                    verify_file(&file, other.algorithm(), &key.public_key_info(), &signature)
+                        .is_err()
+                );
+                assert!(verify_file(
+                    &file,
+                    other.algorithm(),
+                    &other.public_key_info(),
+                    &signature
+                )
+                .is_err());
+            }
+        }
+
+        // fresh Ed25519 and ECDSA keys of the same kind, the RSA key is the same one
+        for (key, other) in keys.iter().zip(&signing_keys()).take(2) {
+            let signature = sign_file(&file, key).unwrap();
+            assert!(
+                verify_file(&file, key.algorithm(), &other.public_key_info(), &signature).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn tampered_contents_dont_verify() {
+        for key in &signing_keys() {
+            let signature = sign_file(&signed_file("notes.txt", b"contents"), key).unwrap();
+            let tampered = signed_file("notes.txt", b"Contents");
+            assert!(matches!(
+                verify_file(
+                    &tampered,
+                    key.algorithm(),
+                    &key.public_key_info(),
+                    &signature
+                ),
+                Err(CryptoError::BadSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn signatures_cover_every_field() {
+        let key = &signing_keys()[0];
+        let file = signed_file("notes.txt", b"contents");
+        let signature = sign_file(&file, key).unwrap();
+        let verify = |file: &SignedFile| {
+            verify_file(file, key.algorithm(), &key.public_key_info(), &signature)
+        };
+        verify(&file).unwrap();
+
+        let changed = [
+            SignedFile {
+                owner: "bob",
+                ..signed_file("notes.txt", b"contents")
+            },
+            SignedFile {
+                name_hash: "other hash",
+                ..signed_file("notes.txt", b"contents")
+            },
+            SignedFile {
+                nonce: &[2; 12],
+                ..signed_file("notes.txt", b"contents")
+            },
+            SignedFile {
+                version: 2,
+                ..signed_file("notes.txt", b"contents")
+            },
+            signed_file("other.txt", b"contents"),
+        ];
+        for file in &changed {
+            assert!(verify(file).is_err());
+        }
+    }
+
+    #[test]
+    fn moving_bytes_between_fields_changes_the_payload() {
+        // the same bytes end to end, split differently between name and contents
+        let a = signed_file("notes.txt", b"contents");
+        let b = signed_file("notes.tx", b"tcontents");
+        assert_ne!(a.signed_bytes(), b.signed_bytes());
+        let c = SignedFile {
+            owner: "alicenotes.txt",
+            ..signed_file("", b"contents")
+        };
+        assert_ne!(a.signed_bytes(), c.signed_bytes());
+    }
+
+    /// A path in an empty directory only the calling test uses
+    fn key_file(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("krypto-key-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("signing-key.pk8")
+    }
+
+    fn pkcs8() -> Vec<u8> {
+        ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+            .unwrap()
+            .as_ref()
+            .to_vec()
+    }
+
+    #[test]
+    fn wrapped_keys_unwrap_with_their_passphrase_only() {
+        let pkcs8 = pkcs8();
+        let wrapped = WrappedKey::wrap(&pkcs8, "passphrase").unwrap();
+        assert_ne!(wrapped.key, pkcs8);
+        assert_eq!(wrapped.unwrap("passphrase").unwrap(), pkcs8);
+        assert!(matches!(
+            wrapped.unwrap("Passphrase"),
+            Err(CryptoError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn changing_the_passphrase_keeps_the_key() {
+        let path = key_file("change");
+        let key = generate_key_pair(&path, "old").unwrap();
+
+        let pkcs8 = match read_key_file(&path).unwrap() {
+            KeyFile::Wrapped(wrapped) => wrapped.unwrap("old").unwrap(),
+            KeyFile::Plain(_) => panic!("new keys are wrapped"),
+        };
+        write_key_file(&path, &WrappedKey::wrap(&pkcs8, "new").unwrap()).unwrap();
+
+        let wrapped = match read_key_file(&path).unwrap() {
+            KeyFile::Wrapped(wrapped) => wrapped,
+            KeyFile::Plain(_) => panic!("rewrapped keys are wrapped"),
+        };
+        assert!(matches!(
+            wrapped.unwrap("old"),
+            Err(CryptoError::Decryption)
+        ));
+        let unwrapped = SigningKey::from_pkcs8(&wrapped.unwrap("new").unwrap()).unwrap();
+        assert_eq!(unwrapped.fingerprint(), key.fingerprint());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn key_files_from_before_wrapping_are_read_as_is() {
+        let path = key_file("plain");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let pkcs8 = pkcs8();
+        std::fs::write(&path, &pkcs8).unwrap();
+        assert!(matches!(read_key_file(&path).unwrap(), KeyFile::Plain(x) if x == pkcs8));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files_are_readable_by_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = key_file("mode");
+        generate_key_pair(&path, "passphrase").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::io;
+
+use reqwest::StatusCode;
+
+use crate::crypto::CryptoError;
+
+/// Everything a command can fail with. Each kind exits with its own code, so
+/// scripts can tell data the server tampered with apart from a network outage.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server couldn't be reached or didn't answer in time
+    Network(reqwest::Error),
+    /// The server answered with an error status
+    Status(StatusCode),
+    /// The server no longer accepts the session token
+    SessionExpired,
+    /// A response or saved state couldn't be parsed
+    Decode(String),
+    /// Contents or a file name couldn't be decrypted with the password
+    Decryption,
+    /// A file or tree head signature doesn't match, or the server key changed
+    BadSignature(&'static str),
+    /// A merkle proof or signed top hash can't be reconciled with the trusted one
+    MerkleMismatch(&'static str),
+    IO(io::Error),
+    /// Bad input or a local key that can't be used
+    Other(String),
+}
+
+impl ClientError {
+    /// The code the client exits with when this was the last error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::Other(_) => 1,
+            ClientError::Network(_) => 2,
+            ClientError::Status(_) | ClientError::SessionExpired => 3,
+            ClientError::Decode(_) => 4,
+            ClientError::IO(_) => 5,
+            ClientError::Decryption => 6,
+            ClientError::BadSignature(_) => 7,
+            ClientError::MerkleMismatch(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Network(e) if e.is_timeout() => write!(f, "Timeout"),
+            ClientError::Network(e) => write!(f, "Network error, {}", e),
+            ClientError::Status(status) => match status.as_u16() {
+                400 => write!(f, "Server refused the request as malformed"),
+                403 => write!(f, "Access denied"),
+                404 => write!(f, "File not found"),
+                409 => write!(f, "Server already has a newer version of the file"),
+                500..=599 => write!(f, "Server error, Statuscode {}", status),
+                _ => write!(f, "Statuscode {}", status),
+            },
+            ClientError::SessionExpired => write!(f, "Session expired"),
+            ClientError::Decode(msg) => write!(f, "Error decoding {}", msg),
+            ClientError::Decryption => write!(f, "Decryption failure, wrong password?"),
+            ClientError::BadSignature(msg) | ClientError::MerkleMismatch(msg) => {
+                write!(f, "{}", msg)
+            }
+            ClientError::IO(e) => write!(f, "IO error, {}", e),
+            ClientError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            ClientError::Decode(format!("response, {}", e))
+        } else {
+            ClientError::Network(e)
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::IO(e)
+    }
+}
+
+impl From<CryptoError> for ClientError {
+    fn from(e: CryptoError) -> Self {
+        match e {
+            CryptoError::IO(e) => ClientError::IO(e),
+            CryptoError::BadPrivateKey => ClientError::Other(String::from("Invalid private key")),
+            CryptoError::BadPublicKey => ClientError::Other(String::from("Invalid public key")),
+            CryptoError::KeyDerivation => ClientError::Other(String::from("Key derivation failed")),
+            CryptoError::BadKdfParams => ClientError::Other(String::from(
+                "Refusing unexpected key derivation parameters",
+            )),
+            CryptoError::Hashing => ClientError::Other(String::from("Hashing the password failed")),
+            CryptoError::OOM => ClientError::Other(String::from("Signing failed")),
+            CryptoError::Encryption => ClientError::Other(String::from("Encryption failed")),
+            CryptoError::Decryption => ClientError::Decryption,
+            CryptoError::BadSignature => ClientError::BadSignature("Invalid signature"),
+        }
+    }
+}
@@ -1,343 +1,796 @@
 use std::io;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 use std::process::exit;
-use std::{fs::File, path::Path};
-
-use reqwest::Url;
-use types::{CreateInfo, FileData, FileInfo, LoginInfo};
-
-use crate::crypto::{decrypt_bytes, hash_password};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use types::merkle::{compute_top_hash, empty_leaf_hash};
+use types::{
+    ConsistencyInfo, ConsistencyProof, CreateInfo, FileData, FileInfo, FileListEntry,
+    KeyDerivation, LoginInfo, MerkleData, PublicKeyInfo, PushResult, Session, SignedTreeHead,
+};
+
+use crate::crypto::{
+    decrypt_bytes, CryptoError, KeyChain, KeyFile, Purpose, SignedFile, SigningKey, WrappedKey,
+};
+use crate::error::ClientError;
+use crate::state::{
+    existing_key_path, from_hex, key_path, pin_server_key, to_hex, SignerKeys, TrustedRoot,
+};
 
 mod crypto;
+mod error;
+mod state;
 
 struct ServerInfo {
-    login_url: Url,          // login
-    create_account_url: Url, // create user account
-    push_url: Url,           // upload a file
-    pull_url: Url,           // request a file
-    delete_url: Url,         // delete a file
-    get_url: Url,            // request metadata about smh
-    list_url: Url,           // request metadata about smh
+    login_url: Url,               // login
+    create_account_url: Url,      // create user account
+    push_url: Url,                // upload a file
+    pull_url: Url,                // request a file
+    delete_url: Url,              // delete a file
+    get_url: Url,                 // request metadata about smh
+    list_url: Url,                // request metadata about smh
+    consistency_url: Url,         // request the changes made since a top hash
+    key_url: Url,                 // request the key tree heads are signed with
+    keys_url: Url,                // register or request public keys files are signed with
+    server_key: Vec<u8>,          // pinned key tree heads have to be signed with
+    token: Option<String>,        // session token from the last login
+    trusted: Option<TrustedRoot>, // top hash last verified for the logged in user
+    signers: Option<SignerKeys>,  // keys files of the logged in user may be signed with
 }
 
 enum CreateStatus {
-    Success,
+    Success(KeyDerivation),
     AccountTaken,
     Error,
 }
 
 enum LoginStatus {
-    Success,
+    Success(KeyDerivation),
     WrongPassword,
     AccountNotFound,
     Error,
 }
 
 impl ServerInfo {
-    fn get_error_text(error: reqwest::Error) -> String {
-        if let Some(status) = error.status() {
-            format!("Statuscode {}", status)
-        } else {
-            if error.is_timeout() {
-                String::from("Timeout")
-            } else if error.is_decode() {
-                String::from("Decoding")
-            } else {
-                String::from("Unknown Error")
+    /// Attaches the session token to a request
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Turns an error status into a message, forgetting the session token if
+    /// the server no longer accepts it so the user gets asked to login again
+    fn check_status(&mut self, response: &Response) -> Result<(), ClientError> {
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            401 => {
+                self.token = None;
+                Err(ClientError::SessionExpired)
             }
+            _ => Err(ClientError::Status(response.status())),
+        }
+    }
+
+    fn trusted(&mut self) -> Result<&mut TrustedRoot, ClientError> {
+        self.trusted
+            .as_mut()
+            .ok_or_else(|| ClientError::Other(String::from("Not logged in")))
+    }
+
+    fn signers(&mut self) -> Result<&mut SignerKeys, ClientError> {
+        self.signers
+            .as_mut()
+            .ok_or_else(|| ClientError::Other(String::from("Not logged in")))
+    }
+
+    /// Requests the server's public key, pinning it the first time
+    pub async fn pin_server_key(&mut self) -> Result<(), ClientError> {
+        let response = reqwest::Client::new()
+            .get(self.key_url.clone())
+            .send()
+            .await?;
+        self.check_status(&response)?;
+
+        let public_key = response.json::<Vec<u8>>().await?;
+        self.server_key = pin_server_key(&public_key)?;
+        Ok(())
+    }
+
+    fn verify_head(&self, signed: &SignedTreeHead) -> Result<(), ClientError> {
+        crypto::verify_tree_head(&self.server_key, signed)
+            .map_err(|_| ClientError::BadSignature("Invalid tree head signature"))
+    }
+
+    /// Makes sure a signed top hash is the trusted one, or that the server
+    /// can prove how the tree got there from the trusted one
+    async fn reconcile(&mut self, signed: &SignedTreeHead) -> Result<(), ClientError> {
+        self.verify_head(signed)?;
+
+        let top_hash = (signed.head.top_hash.clone(), signed.head.tree_size);
+        let head = self.trusted()?.head().map(|(x, size)| (x.to_vec(), size));
+        let (trusted, trusted_size) = match head {
+            Some(head) if head != top_hash => head,
+            _ => return self.trusted()?.check(signed),
+        };
+
+        let response = self
+            .authorized(reqwest::Client::new().get(self.consistency_url.clone()))
+            .json(&ConsistencyInfo {
+                top_hash: trusted,
+                tree_size: trusted_size,
+            })
+            .send()
+            .await?;
+        if response.status().as_u16() == 404 {
+            return Err(ClientError::MerkleMismatch(
+                "Server doesn't know the last trusted top hash, it may have rolled back",
+            ));
+        }
+        self.check_status(&response)?;
+
+        let proof = response.json::<ConsistencyProof>().await?;
+        self.trusted()?.advance(&proof, signed)
+    }
+
+    /// Moves the trusted top hash forward after changing a single leaf. If
+    /// the tree was changed by someone else since it was last trusted, the
+    /// server has to prove every change made in between instead.
+    async fn reconcile_update(
+        &mut self,
+        old_leaf: &[u8],
+        new_leaf: &[u8],
+        tree: &MerkleData,
+        signed: &SignedTreeHead,
+    ) -> Result<(), ClientError> {
+        check_proof(new_leaf, tree, signed)?;
+        self.verify_head(signed)?;
+        if self
+            .trusted()?
+            .update(old_leaf, new_leaf, tree, signed)
+            .is_ok()
+        {
+            return Ok(());
         }
+        self.reconcile(signed).await
+    }
+
+    /// Reads the session token out of a successful login or create response,
+    /// returning what the encryption key is derived with
+    async fn start_session(&mut self, response: Response) -> Result<KeyDerivation, ClientError> {
+        let session = response.json::<Session>().await?;
+        let derivation = &session.key_derivation;
+        crypto::check_derivation(&derivation.salt, &derivation.params)?;
+        self.token = Some(session.token);
+        Ok(session.key_derivation)
     }
 
     // 200 = success
-    // 401 or 418 = account already exists
-    pub async fn create(&self, name: String, password: String) -> Result<CreateStatus, String> {
-        match reqwest::Client::new()
+    // 400 = empty username
+    // 418 = account already exists
+    pub async fn create(
+        &mut self,
+        name: String,
+        password: String,
+    ) -> Result<CreateStatus, ClientError> {
+        let response = reqwest::Client::new()
             .post(self.create_account_url.clone())
             .json(&CreateInfo {
                 name: name.clone(),
-                password: crypto::hash_password(name, password),
+                password: crypto::hash_password(name.clone(), password)?,
+                key_derivation: KeyDerivation {
+                    salt: crypto::generate_salt(),
+                    params: crypto::kdf_params(),
+                },
             })
             .send()
-            .await
-        {
-            Ok(response) => {
-                println!("Got, Statuscode: {}", response.status());
-                Ok(match response.status().as_u16() {
-                    200 => CreateStatus::Success,
-                    401 | 418 => CreateStatus::AccountTaken,
-                    _ => CreateStatus::Error,
-                })
+            .await?;
+
+        println!("Got, Statuscode: {}", response.status());
+        Ok(match response.status().as_u16() {
+            200 => {
+                let key_derivation = self.start_session(response).await?;
+                self.trusted = Some(TrustedRoot::new_account(&name)?);
+                self.signers = Some(SignerKeys::load(&name)?);
+                CreateStatus::Success(key_derivation)
             }
-            Err(error) => Err(ServerInfo::get_error_text(error)),
-        }
+            418 => CreateStatus::AccountTaken,
+            _ => CreateStatus::Error,
+        })
     }
 
     // 200 = success
     // 403 = wrong password
     // 404 = account not found
-    pub async fn login(&self, name: String, password: String) -> Result<LoginStatus, String> {
-        match reqwest::Client::new()
+    pub async fn login(
+        &mut self,
+        name: String,
+        password: String,
+    ) -> Result<LoginStatus, ClientError> {
+        let response = reqwest::Client::new()
             .post(self.login_url.clone())
             .json(&LoginInfo {
                 name: name.clone(),
-                password: crypto::hash_password(name, password),
+                password: crypto::hash_password(name.clone(), password)?,
             })
             .send()
-            .await
-        {
-            Ok(response) => {
-                println!("Got, Statuscode: {}", response.status());
-                Ok(match response.status().as_u16() {
-                    200 => LoginStatus::Success,
-                    403 => LoginStatus::WrongPassword,
-                    404 => LoginStatus::AccountNotFound,
-                    _ => LoginStatus::Error,
-                })
+            .await?;
+
+        println!("Got, Statuscode: {}", response.status());
+        Ok(match response.status().as_u16() {
+            200 => {
+                let key_derivation = self.start_session(response).await?;
+                self.trusted = Some(TrustedRoot::load(&name)?);
+                self.signers = Some(SignerKeys::load(&name)?);
+                LoginStatus::Success(key_derivation)
             }
-            Err(error) => Err(ServerInfo::get_error_text(error)),
+            403 => LoginStatus::WrongPassword,
+            404 => LoginStatus::AccountNotFound,
+            _ => LoginStatus::Error,
+        })
+    }
+
+    /// Registers the public key of a signing key with the logged in account
+    pub async fn register_key(&mut self, key: &SigningKey) -> Result<(), ClientError> {
+        let response = self
+            .authorized(reqwest::Client::new().post(self.keys_url.clone()))
+            .json(&key.public_key_info())
+            .send()
+            .await?;
+        println!("Sent, Statuscode: {}", response.status());
+        self.check_status(&response)
+    }
+
+    /// Public keys registered with the logged in account. Anyone holding a
+    /// session can register a key, so these are only trusted once the user
+    /// confirms one by its fingerprint.
+    async fn registered_keys(&mut self) -> Result<Vec<PublicKeyInfo>, ClientError> {
+        let response = self
+            .authorized(reqwest::Client::new().get(self.keys_url.clone()))
+            .send()
+            .await?;
+        self.check_status(&response)?;
+        Ok(response.json::<Vec<PublicKeyInfo>>().await?)
+    }
+
+    /// Lists the keys registered with the account and whether they are trusted
+    pub async fn list_keys(&mut self) -> Result<(), ClientError> {
+        for key in self.registered_keys().await? {
+            let fingerprint = key.fingerprint();
+            let status = match self.signers()?.find(&fingerprint) {
+                Some(_) => "trusted",
+                None => "untrusted",
+            };
+            println!("{} {}", to_hex(&fingerprint), status);
+        }
+        Ok(())
+    }
+
+    /// Trusts the registered key with the given fingerprint, after the user
+    /// checked it against the one keygen printed on the signing device
+    pub async fn trust_registered_key(&mut self, fingerprint: &[u8]) -> Result<(), ClientError> {
+        match self
+            .registered_keys()
+            .await?
+            .into_iter()
+            .find(|key| key.fingerprint()[..] == fingerprint[..])
+        {
+            Some(key) => self.signers()?.trust(key),
+            None => Err(ClientError::Other(String::from(
+                "No key with that fingerprint is registered",
+            ))),
         }
     }
 
     pub async fn pull_file(
-        &self,
+        &mut self,
         file_name: String,
-        //username: String,
-        password: String,
-        key_pair: &ring::signature::RsaKeyPair,
-    ) -> Result<(), String> {
-        let hash_name = hash_password(password.clone(), file_name.clone());
+        keys: &mut KeyChain,
+    ) -> Result<(), ClientError> {
+        let hash_name = keys.name_hash(&file_name)?;
         println!("File name {}", hash_name);
 
-        match reqwest::Client::new()
-            .get(self.pull_url.clone())
+        let response = self
+            .authorized(reqwest::Client::new().get(self.pull_url.clone()))
             .json(&FileInfo {
                 name_hash: hash_name,
             })
             .send()
-            .await
-        {
-            Ok(response) => {
-                println!("Got, Statuscode: {}", response.status());
-
-                if let Some((file_data, tree)) = response
-                    .json::<Option<(FileData, types::MerkleData)>>()
-                    .await
-                    .map_err(|e| format!("Error reading json {}", e))?
-                {
-                    let mut hash = ring::digest::digest(&ring::digest::SHA256, &file_data.contents);
-
-                    for (side, tree_hash) in tree.hashes {
-                        let rhs = hash.as_ref();
-                        let lhs = tree_hash;
-
-                        let mut concat = if side == types::Side::Left {
-                            lhs.to_vec()
-                        } else {
-                            rhs.to_vec()
-                        };
-
-                        concat.extend_from_slice(if side == types::Side::Left {
-                            &rhs
-                        } else {
-                            &lhs
-                        });
-
-                        hash = ring::digest::digest(&ring::digest::SHA256, &concat[..]);
-                        println!("{:?}", hash.as_ref())
-                    }
-                    println!(">> {:?}",  tree.top_hash);
+            .await?;
+        println!("Got, Statuscode: {}", response.status());
+        self.check_status(&response)?;
+
+        let (file_data, tree, head) = response
+            .json::<(FileData, MerkleData, SignedTreeHead)>()
+            .await?;
+
+        check_proof(&file_data.leaf_hash(), &tree, &head)?;
+        self.reconcile(&head).await?;
+        let public_key = match self.signers()?.find(&file_data.signer) {
+            Some(x) => x.clone(),
+            None => {
+                println!("Signed with untrusted key {}", to_hex(&file_data.signer));
+                return Err(ClientError::BadSignature(
+                    "File was signed with an untrusted key, run trust with its key file or fingerprint",
+                ));
+            }
+        };
 
-                    if hash.as_ref() != tree.top_hash {
-                        return Err(String::from("Invalid hash"));
-                    }
+        let key = keys.key(&file_data.kdf, Purpose::Contents)?;
+        let decrypted_bytes = decrypt_bytes(file_data.contents, &key, file_data.nonce)?;
 
-                    let decrypted_bytes =
-                        decrypt_bytes(file_data.contents, password, file_data.nonce)?;
+        let signed = SignedFile {
+            owner: self.trusted()?.owner(),
+            name: &file_name,
+            name_hash: &file_data.name_hash,
+            nonce: &file_data.nonce,
+            version: file_data.version,
+            contents: &decrypted_bytes,
+        };
+        crypto::verify_file(
+            &signed,
+            file_data.signature_algorithm,
+            &public_key,
+            &file_data.signature,
+        )?;
 
-                    let signature =
-                        crypto::sign_file(&decrypted_bytes, file_name.as_bytes(), key_pair)
-                            .map_err(|_e| String::from("Error verifying signature"))?;
+        let mut file = File::create(file_name)?;
+        file.write_all(&decrypted_bytes)?;
 
-                    if signature != file_data.signature {
-                        return Err(String::from("Invalid signature"));
-                    }
+        Ok(())
+    }
 
-                    let mut file = File::create(file_name)
-                        .map_err(|e| format!("Error creating file, {}", e))?;
+    /// Requests everything about a file except its contents, making sure
+    /// the proof reaches the signed top hash
+    async fn fetch_metadata(
+        &mut self,
+        hash_name: String,
+    ) -> Result<Option<(FileListEntry, MerkleData, SignedTreeHead)>, ClientError> {
+        let response = self
+            .authorized(reqwest::Client::new().get(self.get_url.clone()))
+            .json(&FileInfo {
+                name_hash: hash_name,
+            })
+            .send()
+            .await?;
+        if response.status().as_u16() == 404 {
+            return Ok(None);
+        }
+        self.check_status(&response)?;
 
-                    file.write_all(&decrypted_bytes)
-                        .map_err(|e| format!("Error writing file, {}", e))?;
+        let (entry, tree, head) = response
+            .json::<(FileListEntry, MerkleData, SignedTreeHead)>()
+            .await?;
 
-                    Ok(())
-                } else {
-                    Err(String::from("No data"))
-                }
-            }
-            Err(error) => Err(ServerInfo::get_error_text(error)),
-        }
+        check_proof(&entry.leaf_hash(), &tree, &head)?;
+        Ok(Some((entry, tree, head)))
     }
 
-    pub async fn list_files(&self, password: String) -> Result<(), String> {
-        match reqwest::Client::new()
-            .get(self.list_url.clone())
+    pub async fn file_info(
+        &mut self,
+        file_name: String,
+        keys: &mut KeyChain,
+    ) -> Result<(), ClientError> {
+        let hash_name = keys.name_hash(&file_name)?;
+
+        let (entry, _, head) = self
+            .fetch_metadata(hash_name)
+            .await?
+            .ok_or(ClientError::Status(StatusCode::NOT_FOUND))?;
+        self.reconcile(&head).await?;
+
+        let key = keys.key(&entry.kdf, Purpose::Names)?;
+        let name = decrypt_bytes(entry.name, &key, entry.name_nonce)?;
+        let name = std::str::from_utf8(&name)
+            .map_err(|_| ClientError::Decode(String::from("file name")))?;
+
+        println!("{}, {} bytes", name, entry.size);
+        Ok(())
+    }
+
+    pub async fn delete_file(
+        &mut self,
+        file_name: String,
+        keys: &mut KeyChain,
+    ) -> Result<(), ClientError> {
+        let hash_name = keys.name_hash(&file_name)?;
+
+        // the leaf being emptied is needed to move the trusted top hash forward
+        let old_leaf = match self.fetch_metadata(hash_name.clone()).await? {
+            Some((entry, _, _)) => entry.leaf_hash(),
+            None => return Err(ClientError::Status(StatusCode::NOT_FOUND)),
+        };
+
+        let response = self
+            .authorized(reqwest::Client::new().post(self.delete_url.clone()))
+            .json(&FileInfo {
+                name_hash: hash_name,
+            })
             .send()
+            .await?;
+        println!("Got, Statuscode: {}", response.status());
+        self.check_status(&response)?;
+
+        let (tree, head) = response.json::<(MerkleData, SignedTreeHead)>().await?;
+        self.reconcile_update(&old_leaf, &empty_leaf_hash(), &tree, &head)
             .await
-        {
-            Ok(response) => {
-                let resp = response
-                    .json::<types::FileList>()
-                    .await
-                    .map_err(|e| format!("Error parsing response {}", e))?;
-                for file in resp.list {
-                    if let Ok(file_name_byte_array) =
-                        decrypt_bytes(file.name, password.clone(), file.name_nonce)
-                    {
-                        if let Ok(file_name) = std::str::from_utf8(&file_name_byte_array) {
-                            println!("{}", file_name);
-                        }
-                    }
+    }
+
+    pub async fn list_files(&mut self, keys: &mut KeyChain) -> Result<(), ClientError> {
+        let response = self
+            .authorized(reqwest::Client::new().get(self.list_url.clone()))
+            .send()
+            .await?;
+        self.check_status(&response)?;
+
+        let resp = response.json::<types::FileList>().await?;
+        self.reconcile(&resp.head).await?;
+        for file in resp.list {
+            let key = keys.key(&file.kdf, Purpose::Names)?;
+            if let Ok(file_name_byte_array) = decrypt_bytes(file.name, &key, file.name_nonce) {
+                if let Ok(file_name) = std::str::from_utf8(&file_name_byte_array) {
+                    println!("{}", file_name);
                 }
-                Ok(())
             }
-            Err(error) => Err(ServerInfo::get_error_text(error)),
         }
+        Ok(())
     }
 
     pub async fn push_file(
-        &self,
+        &mut self,
         path: &Path,
-        //username: String,
-        password: String,
-        key_pair: &ring::signature::RsaKeyPair,
-    ) -> Result<(), String> {
-        let mut file = File::open(path).map_err(|e| format!("Error opening file, {}", e))?;
+        keys: &mut KeyChain,
+        key_pair: &SigningKey,
+    ) -> Result<(), ClientError> {
+        let mut file = File::open(path)?;
 
         let file_name = match path.file_name().and_then(|x| x.to_str()) {
             Some(x) => x.to_string(),
-            _ => return Err(String::from("Error getting file name")),
+            _ => return Err(ClientError::Other(String::from("Error getting file name"))),
         };
 
         let mut buffer = Vec::new();
 
-        file.read_to_end(&mut buffer)
-            .map_err(|_| String::from("Error reading file"))?; // TODO ADD ENCRYPTION
-
-        let signature = crypto::sign_file(&buffer, file_name.as_bytes(), key_pair)
-            .map_err(|_e| String::from("Error signing file"))?;
+        file.read_to_end(&mut buffer)?; // TODO ADD ENCRYPTION
 
-        let (nonce, encrypted_file) = crypto::encrypt_bytes(buffer, password.clone())?;
-        let (nonce_name, encrypted_file_name) =
-            crypto::encrypt_bytes(file_name.as_bytes().to_vec(), password.clone())?;
+        let kdf = keys.params();
+        let (nonce, encrypted_file) =
+            crypto::encrypt_bytes(buffer.clone(), &keys.key(&kdf, Purpose::Contents)?)?;
+        let (nonce_name, encrypted_file_name) = crypto::encrypt_bytes(
+            file_name.as_bytes().to_vec(),
+            &keys.key(&kdf, Purpose::Names)?,
+        )?;
 
-        let hash_name = hash_password(password.clone(), file_name);
+        let hash_name = keys.name_hash(&file_name)?;
         println!("File name {}", hash_name);
 
-        match reqwest::Client::new()
-            .post(self.push_url.clone())
-            .json(&FileData {
-                name: encrypted_file_name,
-                contents: encrypted_file,
-                nonce,
-                name_nonce: nonce_name,
-                name_hash: hash_name,
-                signature,
-            })
+        // the leaf being replaced is needed to move the trusted top hash forward
+        let (old_leaf, version) = match self.fetch_metadata(hash_name.clone()).await? {
+            Some((entry, _, _)) => (entry.leaf_hash(), entry.version + 1),
+            None => (empty_leaf_hash(), 1),
+        };
+
+        let signed = SignedFile {
+            owner: self.trusted()?.owner(),
+            name: &file_name,
+            name_hash: &hash_name,
+            nonce: &nonce,
+            version,
+            contents: &buffer,
+        };
+        let signature = crypto::sign_file(&signed, key_pair)?;
+
+        let file_data = FileData {
+            name: encrypted_file_name,
+            contents: encrypted_file,
+            nonce,
+            name_nonce: nonce_name,
+            name_hash: hash_name.clone(),
+            version,
+            signature,
+            signature_algorithm: key_pair.algorithm(),
+            signer: key_pair.public_key_info().fingerprint().to_vec(),
+            kdf,
+        };
+        let new_leaf = file_data.leaf_hash();
+
+        let response = self
+            .authorized(reqwest::Client::new().post(self.push_url.clone()))
+            .json(&file_data)
             .send()
-            .await
-        {
-            Ok(response) => {
-                println!("Sent, Statuscode: {}", response.status());
+            .await?;
+        println!("Sent, Statuscode: {}", response.status());
+        self.check_status(&response)?;
+
+        let pushed = response.json::<PushResult>().await?;
+        if pushed.tree.leaf_index() != Some(pushed.leaf_index) {
+            return Err(ClientError::MerkleMismatch(
+                "Merkle proof is for another leaf",
+            ));
+        }
+        self.reconcile_update(&old_leaf, &new_leaf, &pushed.tree, &pushed.head)
+            .await?;
 
-                Ok(())
-            }
-            Err(error) => Err(ServerInfo::get_error_text(error)),
+        println!("Pushed to leaf {}", pushed.leaf_index);
+        Ok(())
+    }
+}
+
+/// Makes sure a merkle proof reaches the top hash vouched for by a signed
+/// tree head
+fn check_proof(
+    leaf_hash: &[u8],
+    tree: &MerkleData,
+    signed: &SignedTreeHead,
+) -> Result<(), ClientError> {
+    if compute_top_hash(leaf_hash, &tree.hashes) != tree.top_hash {
+        return Err(ClientError::MerkleMismatch("Invalid hash"));
+    }
+    let tree_size = tree
+        .tree_size()
+        .ok_or(ClientError::MerkleMismatch("Merkle proof is too deep"))?;
+    if tree.top_hash != signed.head.top_hash || tree_size != signed.head.tree_size {
+        return Err(ClientError::MerkleMismatch(
+            "Tree head doesn't match the merkle proof",
+        ));
+    }
+    Ok(())
+}
+
+/// Splits "<name> <password>" and checks that the name can be used as an
+/// argon2 salt, printing the reason if it can't
+fn valid_credentials(data: &str) -> Option<(&str, &str)> {
+    let (name, psw) = match data.split_once(' ') {
+        Some(x) => x,
+        None => {
+            println!("Invalid username/password input");
+            return None;
         }
+    };
+
+    // username is used as salt for the login hash and cant be shorter than 8 characters
+    if name.len() < 8 {
+        println!("Too short username");
+        return None;
+    }
+
+    if psw.is_empty() {
+        println!("Password can't be empty");
+        return None;
     }
+
+    Some((name, psw))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // TODO ADD REAL URL
-    let main_url = "http://127.0.0.1:8000";
-    let site = ServerInfo {
-        push_url: Url::parse(&format!("{}/push", main_url))?,
-        pull_url: Url::parse(&format!("{}/pull", main_url))?,
-        delete_url: Url::parse(&format!("{}/delete", main_url))?,
-        get_url: Url::parse(&format!("{}/get", main_url))?,
-        login_url: Url::parse(&format!("{}/login", main_url))?,
-        create_account_url: Url::parse(&format!("{}/create", main_url))?,
-        list_url: Url::parse(&format!("{}/list", main_url))?,
+/// The key files are signed with, which has to be loaded or generated
+/// before pushing
+fn signing_key(key: &Option<SigningKey>) -> Result<&SigningKey, ClientError> {
+    key.as_ref()
+        .ok_or_else(|| ClientError::Other(String::from("No signing key, run keygen to create one")))
+}
+
+/// Accepts files signed with the loaded signing key, which the user holds
+/// the private key of
+fn trust_own_key(site: &mut ServerInfo, key: &Option<SigningKey>) -> Result<(), ClientError> {
+    match key {
+        Some(key) => site.signers()?.trust(key.public_key_info()),
+        None => Ok(()),
+    }
+}
+
+/// Reads a passphrase from its own line of input, without echoing it when
+/// typed into a terminal. Piped input is read as is, so scripts can still
+/// pass the passphrase along with the commands.
+fn read_passphrase(prompt: &str) -> Result<String, ClientError> {
+    if io::stdin().is_terminal() {
+        return Ok(rpassword::prompt_password(prompt)?);
+    }
+
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut buffer = String::new();
+    if io::stdin().read_line(&mut buffer)? == 0 {
+        return Err(ClientError::Other(String::from("No passphrase given")));
+    }
+    Ok(buffer.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Asks for a new passphrase twice, so a typo can't lock the key away
+fn new_passphrase() -> Result<String, ClientError> {
+    let passphrase = read_passphrase("New passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(ClientError::Other(String::from(
+            "Passphrase can't be empty",
+        )));
+    }
+    if read_passphrase("Repeat passphrase: ")? != passphrase {
+        return Err(ClientError::Other(String::from("Passphrases don't match")));
+    }
+    Ok(passphrase)
+}
+
+/// Asks for the passphrase of a wrapped key until it is unwrapped, giving
+/// up after three wrong ones
+fn unlock(wrapped: &WrappedKey) -> Result<Vec<u8>, ClientError> {
+    for _ in 0..3 {
+        match wrapped.unwrap(&read_passphrase("Key passphrase: ")?) {
+            Ok(pkcs8) => return Ok(pkcs8),
+            Err(CryptoError::Decryption) => println!("Wrong passphrase"),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(ClientError::Decryption)
+}
+
+/// Loads the signing key, unlocking it with a passphrase if it is wrapped
+fn load_signing_key(path: &Path) -> Result<SigningKey, ClientError> {
+    let pkcs8 = match crypto::read_key_file(path)? {
+        KeyFile::Plain(pkcs8) => {
+            println!(
+                "Key at {} isn't protected by a passphrase, run passphrase to add one",
+                path.to_string_lossy()
+            );
+            pkcs8
+        }
+        KeyFile::Wrapped(wrapped) => unlock(&wrapped)?,
+    };
+    Ok(SigningKey::from_pkcs8(&pkcs8)?)
+}
+
+/// Wraps the signing key with a new passphrase. The key itself stays the
+/// same, so files signed with it don't have to be signed again.
+fn change_passphrase(path: &Path) -> Result<(), ClientError> {
+    let pkcs8 = match crypto::read_key_file(path)? {
+        KeyFile::Plain(pkcs8) => pkcs8,
+        KeyFile::Wrapped(wrapped) => unlock(&wrapped)?,
     };
+    let passphrase = new_passphrase()?;
+    crypto::write_key_file(path, &WrappedKey::wrap(&pkcs8, &passphrase)?)?;
+    println!("Changed passphrase of {}", path.to_string_lossy());
+    Ok(())
+}
+
+/// Generates a new signing key and uses it from now on. Takes an optional
+/// path to write the key to and `--register` to register its public key
+/// with the logged in account.
+async fn keygen(
+    site: &mut ServerInfo,
+    key: &mut Option<SigningKey>,
+    key_file: &mut PathBuf,
+    args: &str,
+) -> Result<(), ClientError> {
+    let mut path = key_path();
+    let mut register = false;
+    for arg in args.split_whitespace() {
+        match arg {
+            "--register" => register = true,
+            x => path = PathBuf::from(x),
+        }
+    }
+
+    if path.exists() {
+        return Err(ClientError::Other(format!(
+            "A key already exists at {}",
+            path.to_string_lossy()
+        )));
+    }
 
-    let key_path = Path::new("test-rsa-key.pk8");
-    let keypair_unchecked = crypto::get_key_pair(key_path);
-    if keypair_unchecked.is_err() {
+    let new_key = crypto::generate_key_pair(&path, &new_passphrase()?)?;
+    println!("Wrote new key to {}", path.to_string_lossy());
+    println!("Fingerprint {}", new_key.fingerprint());
+    let public_path = crypto::public_key_path(&path);
+    crypto::write_public_key(&public_path, &new_key.public_key_info())?;
+    println!(
+        "Wrote public key to {}, import it with trust on other devices",
+        public_path.to_string_lossy()
+    );
+    if path != key_path() {
         println!(
-            "Error getting keypair at {}",
-            key_path.as_os_str().to_str().unwrap_or_else(|| "ERROR")
+            "Set KRYPTO_KEY to {} to use it next time",
+            path.to_string_lossy()
         );
-        exit(1);
     }
-    let keypair = keypair_unchecked.unwrap();
 
-    let mut buffer = String::new();
-    //let mut username = String::from("admin");
+    site.signers()?.trust(new_key.public_key_info())?;
+    if register {
+        site.register_key(&new_key).await?;
+        println!("Registered public key");
+    }
+    *key = Some(new_key);
+    *key_file = path;
+    Ok(())
+}
 
-    let userpassword = String::from("hunter42");
+/// Writes the public key of the loaded key next to it, for keys that were
+/// made before keygen wrote public keys
+fn export_public_key(key: &Option<SigningKey>, key_file: &Path) -> Result<(), ClientError> {
+    let path = crypto::public_key_path(key_file);
+    crypto::write_public_key(&path, &signing_key(key)?.public_key_info())?;
+    println!("Wrote public key to {}", path.to_string_lossy());
+    Ok(())
+}
 
-    // password is used as salt and cant be shorter than 8 characters
-    if userpassword.len() < 8 {
-        println!("Too short password");
-        exit(1);
+/// Trusts the public key in a file written by keygen, so files signed with
+/// its private key can be pulled on a device that doesn't hold it. A
+/// fingerprint instead of a path trusts the registered key it belongs to.
+async fn trust_key(site: &mut ServerInfo, arg: &str) -> Result<(), ClientError> {
+    match from_hex(arg) {
+        Some(fingerprint) if fingerprint.len() == 32 => {
+            site.trust_registered_key(&fingerprint).await
+        }
+        _ => {
+            let key = crypto::read_public_key(Path::new(arg))?;
+            site.signers()?.trust(key)
+        }
     }
+}
 
-    /*println!("{}", format!("Login or Create account at {}", main_url));
+/// Reads a line of input, exiting with the given code once input runs out
+fn read_command(buffer: &mut String, code: i32) -> io::Result<()> {
+    buffer.clear();
+    if io::stdin().read_line(buffer)? == 0 {
+        exit(code);
+    }
+    Ok(())
+}
+
+/// Prints the error of a failed command, returning the code the client
+/// exits with if it is the last one
+fn report(result: Result<(), ClientError>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("{}", e);
+            e.exit_code()
+        }
+    }
+}
+
+/// Asks for login or account creation until one succeeds, returning the
+/// keys of the logged in user
+async fn prompt_login(site: &mut ServerInfo, mut code: i32) -> io::Result<KeyChain> {
+    let mut buffer = String::new();
 
-    let mut username = String::new();
-    let mut userpassword = String::new();
     loop {
-        buffer.clear();
-        io::stdin().read_line(&mut buffer)?;
+        read_command(&mut buffer, code)?;
 
         match buffer.trim().split_once(" ") {
             Some((prefix, data)) => match prefix {
                 "login" => {
-                    if let Some((name, psw)) = data.split_once(" ") {
+                    if let Some((name, psw)) = valid_credentials(data) {
                         match site.login(name.to_string(), psw.to_string()).await {
                             Ok(status) => match status {
-                                LoginStatus::Success => {
-                                    username = name.to_string();
-                                    userpassword = psw.to_string();
+                                LoginStatus::Success(key_derivation) => {
                                     println!("Login successful");
-                                    break;
+                                    return Ok(KeyChain::new(psw.to_string(), key_derivation));
                                 }
                                 LoginStatus::WrongPassword => println!("Wrong password"),
                                 LoginStatus::AccountNotFound => println!("Account Not Found"),
                                 LoginStatus::Error => println!("Server error"),
                             },
-                            Err(msg) => println!("{}", msg),
+                            Err(e) => code = report(Err(e)),
                         }
-                    } else {
-                        println!("Invalid username/password input");
                     }
                 }
                 "create" => {
-                    if let Some((name, psw)) = data.split_once(" ") {
+                    if let Some((name, psw)) = valid_credentials(data) {
                         match site.create(name.to_string(), psw.to_string()).await {
                             Ok(status) => match status {
-                                CreateStatus::Success => {
-                                    username = name.to_string();
-                                    userpassword = psw.to_string();
+                                CreateStatus::Success(key_derivation) => {
                                     println!("Login successful");
-                                    break;
+                                    return Ok(KeyChain::new(psw.to_string(), key_derivation));
                                 }
                                 CreateStatus::AccountTaken => println!("Account already taken"),
                                 CreateStatus::Error => println!("Server error"),
                             },
-                            Err(msg) => println!("{}", msg),
+                            Err(e) => code = report(Err(e)),
                         }
-                    } else {
-                        println!("Invalid username/password input");
                     }
                 }
                 _ => {
@@ -346,66 +799,117 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             },
             _ => match &buffer.trim()[0..] {
                 "exit" | "quit" | "q" => {
-                    exit(0);
+                    exit(code);
                 }
                 _ => {
                     println!("Invalid login");
                 }
             },
         }
-    }*/
+    }
+}
 
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // TODO ADD REAL URL
+    let main_url = "http://127.0.0.1:8000";
+    let mut site = ServerInfo {
+        push_url: Url::parse(&format!("{}/push", main_url))?,
+        pull_url: Url::parse(&format!("{}/pull", main_url))?,
+        delete_url: Url::parse(&format!("{}/delete", main_url))?,
+        get_url: Url::parse(&format!("{}/get", main_url))?,
+        login_url: Url::parse(&format!("{}/login", main_url))?,
+        create_account_url: Url::parse(&format!("{}/create", main_url))?,
+        list_url: Url::parse(&format!("{}/list", main_url))?,
+        consistency_url: Url::parse(&format!("{}/consistency", main_url))?,
+        key_url: Url::parse(&format!("{}/key", main_url))?,
+        keys_url: Url::parse(&format!("{}/keys", main_url))?,
+        server_key: Vec::new(),
+        token: None,
+        trusted: None,
+        signers: None,
+    };
+
+    let mut key_file = existing_key_path();
+    if key_file != key_path() {
+        println!(
+            "Using the key at its old location {}, run keygen to replace it",
+            key_file.to_string_lossy()
+        );
+    }
+    let mut keypair = match load_signing_key(&key_file) {
+        Ok(x) => Some(x),
+        Err(ClientError::IO(e)) if e.kind() == io::ErrorKind::NotFound => {
+            println!(
+                "No signing key at {}, run keygen to create one",
+                key_file.to_string_lossy()
+            );
+            None
+        }
+        Err(e) => {
+            println!(
+                "Error getting keypair at {}, {}",
+                key_file.to_string_lossy(),
+                e
+            );
+            exit(e.exit_code());
+        }
+    };
+
+    if let Err(e) = site.pin_server_key().await {
+        println!("{}", e);
+        exit(e.exit_code());
+    }
+
+    println!("Login or Create account at {}", main_url);
+    let mut keys = prompt_login(&mut site, 0).await?;
+
+    // the client exits with the code of the last command, so scripts can
+    // tell what went wrong
+    let mut code = report(trust_own_key(&mut site, &keypair));
+    let mut buffer = String::new();
     loop {
-        buffer.clear();
-        io::stdin().read_line(&mut buffer)?;
+        read_command(&mut buffer, code)?;
 
-        match buffer.trim().split_once(" ") {
+        code = match buffer.trim().split_once(" ") {
             Some((prefix, data)) => match prefix {
-                "pull" => {
-                    if let Err(msg) = site
-                        .pull_file(
-                            data.to_string(),
-                            //username.clone(),
-                            userpassword.clone(),
-                            &keypair,
-                        )
-                        .await
-                    {
-                        println!("{}", msg)
-                    }
-                }
-                "push" => {
-                    if let Err(msg) = site
-                        .push_file(
-                            Path::new(data.trim()),
-                            //username.clone(),
-                            userpassword.clone(),
-                            &keypair,
-                        )
-                        .await
-                    {
-                        println!("{}", msg)
-                    }
-                }
+                // checked against the trusted key the file names, no private key needed
+                "pull" => report(site.pull_file(data.to_string(), &mut keys).await),
+                "push" => report(match signing_key(&keypair) {
+                    Ok(key) => site.push_file(Path::new(data.trim()), &mut keys, key).await,
+                    Err(e) => Err(e),
+                }),
+                "keygen" => report(keygen(&mut site, &mut keypair, &mut key_file, data).await),
+                "trust" => report(trust_key(&mut site, data.trim()).await),
+                "info" => report(site.file_info(data.to_string(), &mut keys).await),
+                "delete" => report(site.delete_file(data.to_string(), &mut keys).await),
                 _ => {
-                    println!("Invalid prefix")
+                    println!("Invalid prefix");
+                    code
                 }
             },
             _ => match &buffer.trim()[0..] {
-                "list" => {
-                    if let Err(msg) = site.list_files(userpassword.clone()).await {
-                        println!("{}", msg)
-                    }
-                }
+                "list" => report(site.list_files(&mut keys).await),
+                "keys" => report(site.list_keys().await),
+                "keygen" => report(keygen(&mut site, &mut keypair, &mut key_file, "").await),
+                "passphrase" => report(change_passphrase(&key_file)),
+                "pubkey" => report(export_public_key(&keypair, &key_file)),
                 "exit" | "quit" | "q" => {
-                    exit(0);
+                    exit(code);
                 }
                 _ => {
                     println!("Invalid input");
+                    code
                 }
             },
+        };
+
+        if site.token.is_none() {
+            println!("Please login again");
+            keys = prompt_login(&mut site, code).await?;
+            if let Err(e) = trust_own_key(&mut site, &keypair) {
+                code = report(Err(e));
+            }
         }
     }
-
-    Ok(())
 }
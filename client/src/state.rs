@@ -0,0 +1,301 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use types::merkle::{empty_leaf_hash, verify_consistency, verify_update};
+use types::{ConsistencyProof, MerkleData, PublicKeyInfo, SignedTreeHead};
+
+use crate::error::ClientError;
+
+static STATE_DIR_VAR: &str = "KRYPTO_DIR";
+static KEY_VAR: &str = "KRYPTO_KEY";
+
+/// The directory the client keeps what it has learned about the server in
+pub fn state_dir() -> PathBuf {
+    match env::var_os(STATE_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(".krypto"),
+    }
+}
+
+/// Where the private key files are signed with is kept, in the state
+/// directory unless overridden. Keys used to be read from the working
+/// directory instead, see `existing_key_path`.
+pub fn key_path() -> PathBuf {
+    match env::var_os(KEY_VAR) {
+        Some(path) => PathBuf::from(path),
+        None => state_dir().join("signing-key.pk8"),
+    }
+}
+
+/// Where the client read its key from before keys were kept in the state
+/// directory, relative to the working directory
+static LEGACY_KEY_FILE: &str = "test-rsa-key.pk8";
+
+/// Where the signing key is loaded from. Unless `KRYPTO_KEY` is set, a key
+/// at the old location is still used until one is generated in the state
+/// directory, so updating doesn't orphan it.
+pub fn existing_key_path() -> PathBuf {
+    let path = key_path();
+    let legacy = PathBuf::from(LEGACY_KEY_FILE);
+    if env::var_os(KEY_VAR).is_none() && !path.exists() && legacy.exists() {
+        return legacy;
+    }
+    path
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Name of the file in the state directory holding the server's public key
+static SERVER_KEY_FILE: &str = "server.key";
+
+/// Pins the server's public key the first time it is seen, refusing any
+/// other key from then on
+pub fn pin_server_key(public_key: &[u8]) -> Result<Vec<u8>, ClientError> {
+    let path = state_dir().join(SERVER_KEY_FILE);
+    match fs::read_to_string(&path) {
+        Ok(x) => {
+            let pinned = from_hex(x.trim()).ok_or_else(|| {
+                ClientError::Decode(format!("server key in {}", path.to_string_lossy()))
+            })?;
+            if pinned != public_key {
+                return Err(ClientError::BadSignature(
+                    "Server key doesn't match the pinned one, refusing to connect",
+                ));
+            }
+            Ok(pinned)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("Pinning server key {}", to_hex(public_key));
+            fs::create_dir_all(state_dir())?;
+            fs::write(&path, to_hex(public_key))?;
+            Ok(public_key.to_vec())
+        }
+        Err(e) => Err(ClientError::IO(e)),
+    }
+}
+
+/// The last top hash the client verified for an account, along with the
+/// size of the tree it belongs to. Any other top hash the server answers with
+/// is refused unless the server can prove how the tree got there from this
+/// one, so it can't roll back or rewrite the tree unnoticed.
+///
+/// The signed tree head vouching for the trusted top hash is saved next to
+/// it, as evidence in case the server later contradicts itself.
+pub struct TrustedRoot {
+    owner: String,
+    head: Option<(Vec<u8>, u64)>,
+}
+
+impl TrustedRoot {
+    fn path(&self, extension: &str) -> PathBuf {
+        state_dir().join(format!("{}.{}", to_hex(self.owner.as_bytes()), extension))
+    }
+
+    /// Loads the top hash saved for an account, if there is one
+    pub fn load(owner: &str) -> Result<Self, ClientError> {
+        let mut trusted = Self {
+            owner: owner.to_string(),
+            head: None,
+        };
+        let path = trusted.path("root");
+        trusted.head = match fs::read_to_string(&path) {
+            Ok(x) => Some(Self::parse(&x).ok_or_else(|| {
+                ClientError::Decode(format!("trusted top hash in {}", path.to_string_lossy()))
+            })?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(ClientError::IO(e)),
+        };
+        Ok(trusted)
+    }
+
+    /// Saved as the hex encoded top hash followed by the tree size
+    fn parse(saved: &str) -> Option<(Vec<u8>, u64)> {
+        let (top_hash, tree_size) = saved.trim().split_once(' ')?;
+        Some((from_hex(top_hash)?, tree_size.parse().ok()?))
+    }
+
+    /// A newly created account starts out with an empty tree
+    pub fn new_account(owner: &str) -> Result<Self, ClientError> {
+        let mut trusted = Self {
+            owner: owner.to_string(),
+            head: None,
+        };
+        trusted.trust(&empty_leaf_hash(), 1)?;
+        Ok(trusted)
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn head(&self) -> Option<(&[u8], u64)> {
+        self.head.as_ref().map(|(x, size)| (&x[..], *size))
+    }
+
+    fn trust(&mut self, top_hash: &[u8], tree_size: u64) -> Result<(), ClientError> {
+        self.head = Some((top_hash.to_vec(), tree_size));
+        fs::create_dir_all(state_dir())?;
+        fs::write(
+            self.path("root"),
+            format!("{} {}", to_hex(top_hash), tree_size),
+        )?;
+        Ok(())
+    }
+
+    /// Trusts the top hash of a signed tree head, which has to be verified
+    /// against the server key beforehand
+    fn trust_signed(&mut self, signed: &SignedTreeHead) -> Result<(), ClientError> {
+        self.trust(&signed.head.top_hash, signed.head.tree_size)?;
+        let evidence = serde_json::to_string(signed)
+            .map_err(|e| ClientError::Other(format!("Error encoding tree head, {}", e)))?;
+        fs::write(self.path("head"), evidence)?;
+        Ok(())
+    }
+
+    fn check_owner(&self, signed: &SignedTreeHead) -> Result<(), ClientError> {
+        if signed.head.owner != self.owner {
+            return Err(ClientError::MerkleMismatch(
+                "Tree head was signed for another account",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Makes sure the server signed the trusted top hash. If nothing has
+    /// been trusted yet the first signed top hash is trusted from now on.
+    pub fn check(&mut self, signed: &SignedTreeHead) -> Result<(), ClientError> {
+        self.check_owner(signed)?;
+        let head = &signed.head;
+        match self.head() {
+            Some(trusted) if trusted == (&head.top_hash[..], head.tree_size) => {
+                self.trust_signed(signed)
+            }
+            Some(_) => Err(ClientError::MerkleMismatch(
+                "Top hash doesn't match the last trusted one, the server may have rolled back",
+            )),
+            None => {
+                println!("Trusting top hash {} from now on", to_hex(&head.top_hash));
+                self.trust_signed(signed)
+            }
+        }
+    }
+
+    /// Moves the trusted top hash forward to a signed one the server proved
+    /// can be reached from it
+    pub fn advance(
+        &mut self,
+        proof: &ConsistencyProof,
+        signed: &SignedTreeHead,
+    ) -> Result<(), ClientError> {
+        self.check_owner(signed)?;
+        let (trusted, trusted_size) = match self.head() {
+            Some(x) => x,
+            None => return self.check(signed),
+        };
+
+        let head = &signed.head;
+        match verify_consistency(trusted, trusted_size, proof) {
+            Some(reached) if reached == (head.top_hash.clone(), head.tree_size) => {
+                self.trust_signed(signed)
+            }
+            _ => Err(ClientError::MerkleMismatch(
+                "Invalid consistency proof, the server may have rewritten its history",
+            )),
+        }
+    }
+
+    /// Moves the trusted top hash forward after the client itself changed
+    /// one leaf, making sure nothing else in the tree changed
+    pub fn update(
+        &mut self,
+        old_leaf: &[u8],
+        new_leaf: &[u8],
+        tree: &MerkleData,
+        signed: &SignedTreeHead,
+    ) -> Result<(), ClientError> {
+        self.check_owner(signed)?;
+        let tree_size = tree
+            .tree_size()
+            .ok_or(ClientError::MerkleMismatch("Merkle proof is too deep"))?;
+        if signed.head.top_hash != tree.top_hash || signed.head.tree_size != tree_size {
+            return Err(ClientError::MerkleMismatch(
+                "Tree head doesn't match the merkle proof",
+            ));
+        }
+
+        let (trusted, trusted_size) = match self.head() {
+            Some(x) => x,
+            None => return self.check(signed),
+        };
+
+        if tree_size < trusted_size
+            || !verify_update(trusted, old_leaf, new_leaf, &tree.hashes, &tree.top_hash)
+        {
+            return Err(ClientError::MerkleMismatch(
+                "New top hash can't be reconciled with the last trusted one",
+            ));
+        }
+
+        self.trust_signed(signed)
+    }
+}
+
+/// Public keys the client accepts file signatures from for an account. Every
+/// file names the key it was signed with by its fingerprint, so files signed
+/// with different keys, or with a key since replaced, all keep verifying.
+pub struct SignerKeys {
+    path: PathBuf,
+    keys: Vec<PublicKeyInfo>,
+}
+
+impl SignerKeys {
+    /// Loads the keys saved for an account, starting without any if none
+    /// were trusted yet
+    pub fn load(owner: &str) -> Result<Self, ClientError> {
+        let path = state_dir().join(format!("{}.keys", to_hex(owner.as_bytes())));
+        let keys = match fs::read(&path) {
+            Ok(x) => serde_json::from_slice(&x).map_err(|_| {
+                ClientError::Decode(format!("trusted keys in {}", path.to_string_lossy()))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(ClientError::IO(e)),
+        };
+        Ok(Self { path, keys })
+    }
+
+    /// The trusted key with the given fingerprint
+    pub fn find(&self, fingerprint: &[u8]) -> Option<&PublicKeyInfo> {
+        self.keys
+            .iter()
+            .find(|key| key.fingerprint()[..] == fingerprint[..])
+    }
+
+    /// Accepts signatures made with a key from now on
+    pub fn trust(&mut self, key: PublicKeyInfo) -> Result<(), ClientError> {
+        if self.keys.contains(&key) {
+            return Ok(());
+        }
+        println!("Trusting signing key {}", to_hex(&key.fingerprint()));
+        self.keys.push(key);
+
+        let saved = serde_json::to_vec(&self.keys)
+            .map_err(|e| ClientError::Other(format!("Error encoding trusted keys, {}", e)))?;
+        fs::create_dir_all(state_dir())?;
+        fs::write(&self.path, saved)?;
+        Ok(())
+    }
+}
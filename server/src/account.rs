@@ -0,0 +1,317 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use types::merkle::sha256;
+use types::{CreateInfo, KeyDerivation, PublicKeyInfo};
+
+use super::cache::ContentCache;
+use super::data::{Files, SavedFiles};
+use super::error::ServerError;
+use super::file::to_hex;
+
+/// How long a session token is accepted after logging in
+const SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Name of the file in the save directory listing every account and file
+static INDEX_FILE: &str = "index.json";
+
+#[derive(Debug)]
+pub enum AccountError {
+    AccountTaken,
+    AccountNotFound,
+    WrongPassword,
+    Hashing,
+    IO(io::Error),
+}
+
+#[derive(Debug)]
+pub struct Account {
+    /// Argon2 encoded hash of the password sent by the client, including
+    /// the random salt generated when the account was created
+    password_hash: String,
+    /// Salt and cost the client derives its encryption key with, handed out
+    /// on login so every device derives the same key
+    key_derivation: KeyDerivation,
+    /// Public keys the owner registered for signing files
+    public_keys: Vec<PublicKeyInfo>,
+    /// Every file pushed by this account, kept separate from other accounts
+    files: Files,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedAccount {
+    password_hash: String,
+    key_derivation: KeyDerivation,
+    #[serde(default)]
+    public_keys: Vec<PublicKeyInfo>,
+    files: SavedFiles,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    name: String,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+    /// Logged in users, indexed by their bearer token
+    sessions: HashMap<String, Session>,
+    /// Where the index and the contents of every account's files are saved
+    dir: PathBuf,
+    /// Which file contents are held in memory, shared by every account
+    cache: ContentCache,
+}
+
+impl Accounts {
+    /// Loads every account saved in `dir`, or starts without any if nothing
+    /// has been saved there yet
+    pub fn load(dir: PathBuf, cache: ContentCache) -> io::Result<Self> {
+        let mut accounts = Accounts {
+            accounts: HashMap::new(),
+            sessions: HashMap::new(),
+            dir,
+            cache,
+        };
+
+        let index = match fs::read_to_string(accounts.dir.join(INDEX_FILE)) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(accounts),
+            Err(e) => return Err(e),
+        };
+        let saved: HashMap<String, SavedAccount> = rocket::serde::json::from_str(&index)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        for (name, account) in saved {
+            let files = Files::restore(account.files, Self::files_dir(&accounts.dir, &name))?;
+            accounts.accounts.insert(
+                name,
+                Account {
+                    password_hash: account.password_hash,
+                    key_derivation: account.key_derivation,
+                    public_keys: account.public_keys,
+                    files,
+                },
+            );
+        }
+
+        Ok(accounts)
+    }
+
+    /// Writes the index of every account and file, which has to be done
+    /// after every change for it to survive a restart. Contents the old
+    /// index pointed to are only removed once the new one is in place.
+    pub fn save(&mut self) -> io::Result<()> {
+        let saved = self
+            .accounts
+            .iter()
+            .map(|(name, account)| {
+                let saved = SavedAccount {
+                    password_hash: account.password_hash.clone(),
+                    key_derivation: account.key_derivation.clone(),
+                    public_keys: account.public_keys.clone(),
+                    files: account.files.saved(),
+                };
+                (name, saved)
+            })
+            .collect::<HashMap<_, _>>();
+        let index = rocket::serde::json::to_string(&saved).map_err(io::Error::other)?;
+
+        // write to a temporary file first so a crash can't leave half an index behind
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        fs::write(&tmp, index)?;
+        fs::rename(tmp, self.dir.join(INDEX_FILE))?;
+
+        for account in self.accounts.values_mut() {
+            account.files.remove_obsolete();
+        }
+        Ok(())
+    }
+
+    /// Usernames can contain any character, so every account's files are
+    /// kept in a directory named after the hash of the username
+    fn files_dir(dir: &Path, name: &str) -> PathBuf {
+        dir.join(to_hex(&sha256(name.as_bytes())))
+    }
+
+    /// Adds an account with a password hashed by `hash_password`
+    pub fn create(&mut self, info: CreateInfo, password_hash: String) -> Result<(), AccountError> {
+        if self.accounts.contains_key(&info.name) {
+            return Err(AccountError::AccountTaken);
+        }
+
+        let files_dir = Self::files_dir(&self.dir, &info.name);
+        fs::create_dir_all(&files_dir).map_err(AccountError::IO)?;
+
+        self.accounts.insert(
+            info.name,
+            Account {
+                password_hash,
+                key_derivation: info.key_derivation,
+                public_keys: Vec::new(),
+                files: Files::new(files_dir),
+            },
+        );
+        self.save().map_err(AccountError::IO)
+    }
+
+    /// The password hash of an account, to be checked with `verify_password`,
+    /// along with what the client needs to derive its encryption key
+    pub fn credentials(&self, name: &str) -> Result<(String, KeyDerivation), AccountError> {
+        let account = self
+            .accounts
+            .get(name)
+            .ok_or(AccountError::AccountNotFound)?;
+        Ok((
+            account.password_hash.clone(),
+            account.key_derivation.clone(),
+        ))
+    }
+
+    /// Creates a new session for an account, returning the bearer token
+    /// the client has to attach to every following request. Expired
+    /// sessions are dropped first, so tokens that are never presented
+    /// again don't pile up.
+    pub fn start_session(&mut self, name: &str) -> Result<String, AccountError> {
+        let now = Instant::now();
+        self.sessions.retain(|_, session| session.expires > now);
+
+        let mut bytes = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| AccountError::Hashing)?;
+        let token = to_hex(&bytes);
+
+        self.sessions.insert(
+            token.clone(),
+            Session {
+                name: name.to_string(),
+                expires: now + SESSION_LIFETIME,
+            },
+        );
+        Ok(token)
+    }
+
+    /// Looks up which account a token belongs to, forgetting it if it has expired
+    pub fn session_user(&mut self, token: &str) -> Option<User> {
+        let session = self.sessions.get(token)?;
+        if session.expires <= Instant::now() {
+            self.sessions.remove(token);
+            return None;
+        }
+        Some(User(session.name.clone()))
+    }
+
+    /// Adds a public key to an account unless it was registered before
+    pub fn register_key(&mut self, user: &User, info: PublicKeyInfo) -> io::Result<()> {
+        let public_keys = &mut self.accounts.get_mut(&user.0).unwrap().public_keys;
+        if !public_keys.contains(&info) {
+            public_keys.push(info);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn public_keys(&self, user: &User) -> &[PublicKeyInfo] {
+        &self.accounts[&user.0].public_keys
+    }
+
+    /// The file store belonging to the given user. Accounts are never
+    /// removed, so a `User` that passed the request guard always has one.
+    pub fn files(&self, user: &User) -> &Files {
+        &self.accounts[&user.0].files
+    }
+
+    pub fn files_mut(&mut self, user: &User) -> &mut Files {
+        &mut self.accounts.get_mut(&user.0).unwrap().files
+    }
+
+    /// Records that the contents of a file were paged into memory, evicting
+    /// the least recently used contents if that goes over the memory budget
+    pub fn used(&mut self, user: &User, name_hash: &str) {
+        let size = match self.files(user).size_of(name_hash) {
+            Some(x) => x,
+            None => return,
+        };
+
+        for (owner, name_hash) in self.cache.used(&user.0, name_hash, size) {
+            if let Some(account) = self.accounts.get(&owner) {
+                account.files.evict(&name_hash);
+            }
+        }
+    }
+
+    /// Stops tracking the contents of a file that was replaced or deleted
+    pub fn forget(&mut self, user: &User, name_hash: &str) {
+        self.cache.forget(&user.0, name_hash);
+    }
+}
+
+/// Hashes the password for a new account with a random salt. Argon2 is slow
+/// on purpose, so this is done without holding the lock on the accounts.
+pub fn hash_password(password: &str) -> Result<String, AccountError> {
+    let mut salt = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| AccountError::Hashing)?;
+
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+        .map_err(|_| AccountError::Hashing)
+}
+
+/// Checks a password against the hash from `Accounts::credentials`, also
+/// without holding the lock
+pub fn verify_password(password_hash: &str, password: &str) -> Result<(), AccountError> {
+    match argon2::verify_encoded(password_hash, password.as_bytes()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(AccountError::WrongPassword),
+        Err(_) => Err(AccountError::Hashing),
+    }
+}
+
+/// The account a request is made on behalf of, authenticated by a
+/// `Authorization: Bearer <token>` header from `/login` or `/create`
+#[derive(Debug)]
+pub struct User(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = ServerError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|x| x.strip_prefix("Bearer "))
+        {
+            Some(x) => x,
+            None => return Outcome::Error((Status::Unauthorized, ServerError::Unauthorized)),
+        };
+
+        let accounts = req
+            .rocket()
+            .state::<Mutex<Accounts>>()
+            .expect("Accounts is managed in launch");
+
+        let mut accounts = match accounts.lock() {
+            Ok(x) => x,
+            Err(e) => {
+                let e = ServerError::from(e);
+                return Outcome::Error((e.status(), e));
+            }
+        };
+
+        match accounts.session_user(token) {
+            Some(user) => Outcome::Success(user),
+            None => Outcome::Error((Status::Unauthorized, ServerError::Unauthorized)),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+static BUDGET_VAR: &str = "SERVER_MEMORY_BUDGET";
+const DEFAULT_BUDGET: usize = 256 * 1024 * 1024;
+
+/// Files are identified by the account owning them and their name hash,
+/// which stays the same when a file is overwritten
+type Key = (String, String);
+
+/// Keeps track of which file contents are held in memory, picking the least
+/// recently used ones to evict to disk once they take up more than the budget
+#[derive(Debug)]
+pub struct ContentCache {
+    /// Number of content bytes allowed to stay in memory
+    budget: usize,
+    resident: usize,
+    tick: u64,
+    /// Resident files ordered by when they were last used
+    by_use: BTreeMap<u64, Key>,
+    /// Last use and size of every resident file
+    files: HashMap<Key, (u64, usize)>,
+}
+
+impl ContentCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            resident: 0,
+            tick: 0,
+            by_use: BTreeMap::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Reads the budget in bytes from `SERVER_MEMORY_BUDGET`
+    pub fn from_env() -> Self {
+        let budget = env::var(BUDGET_VAR)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_BUDGET);
+        Self::new(budget)
+    }
+
+    /// Marks a file as resident and recently used, returning the files that
+    /// have to be evicted to get back within the budget
+    pub fn used(&mut self, user: &str, name_hash: &str, size: usize) -> Vec<Key> {
+        let key = (user.to_string(), name_hash.to_string());
+        self.forget(&key.0, &key.1);
+
+        self.tick += 1;
+        self.by_use.insert(self.tick, key.clone());
+        self.files.insert(key, (self.tick, size));
+        self.resident += size;
+
+        let mut evicted = vec![];
+        while self.resident > self.budget {
+            let key = match self.by_use.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.forget(&key.0, &key.1);
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    /// Stops tracking a file, which has to be done when it is evicted,
+    /// replaced or deleted
+    pub fn forget(&mut self, user: &str, name_hash: &str) {
+        let key = (user.to_string(), name_hash.to_string());
+        if let Some((tick, size)) = self.files.remove(&key) {
+            self.by_use.remove(&tick);
+            self.resident -= size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name_hash: &str) -> Key {
+        (String::from("user"), name_hash.to_string())
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let mut cache = ContentCache::new(10);
+        assert!(cache.used("user", "a", 4).is_empty());
+        assert!(cache.used("user", "b", 4).is_empty());
+        // using a again makes b the oldest
+        assert!(cache.used("user", "a", 4).is_empty());
+        assert_eq!(cache.used("user", "c", 4), vec![key("b")]);
+        assert_eq!(cache.resident, 8);
+    }
+
+    #[test]
+    fn evicts_until_within_budget() {
+        let mut cache = ContentCache::new(10);
+        cache.used("user", "a", 3);
+        cache.used("user", "b", 3);
+        cache.used("user", "c", 3);
+        assert_eq!(
+            cache.used("user", "d", 8),
+            vec![key("a"), key("b"), key("c")]
+        );
+        assert_eq!(cache.resident, 8);
+    }
+
+    #[test]
+    fn file_over_budget_is_evicted_itself() {
+        let mut cache = ContentCache::new(10);
+        cache.used("user", "a", 4);
+        assert_eq!(cache.used("user", "b", 20), vec![key("a"), key("b")]);
+        assert_eq!(cache.resident, 0);
+    }
+
+    #[test]
+    fn forgotten_files_free_their_budget() {
+        let mut cache = ContentCache::new(10);
+        cache.used("user", "a", 6);
+        cache.forget("user", "a");
+        assert!(cache.used("user", "b", 6).is_empty());
+        // forgetting a file that isn't resident changes nothing
+        cache.forget("user", "a");
+        assert_eq!(cache.resident, 6);
+    }
+
+    #[test]
+    fn accounts_are_kept_apart() {
+        let mut cache = ContentCache::new(10);
+        cache.used("alice", "a", 6);
+        cache.used("bob", "a", 2);
+        assert_eq!(cache.resident, 8);
+        assert_eq!(
+            cache.used("bob", "b", 4),
+            vec![(String::from("alice"), String::from("a"))]
+        );
+    }
+}
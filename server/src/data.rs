@@ -1,82 +1,556 @@
-use ring::digest::{digest, Digest, SHA256};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use types::{FileData as NetworkFileData, FileInfo};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use types::merkle::{empty_leaf_hash, sha256};
+use types::{
+    ConsistencyInfo, ConsistencyProof, FileData as NetworkFileData, FileInfo, FileListEntry,
+    TreeUpdate,
+};
 
-use std::io::{self, prelude::*};
-
-use super::file::File;
-use super::merkle_tree::MerkleTree;
+use super::file::{to_hex, File, PersistentFile};
+use super::history::History;
+use super::merkle_tree::{Hash, MerkleTree};
 
 #[derive(Debug)]
 pub struct Files {
     file_id: u64,
     tree: MerkleTree,
     file_map: HashMap<String, u64>,
+    /// IDs below `file_id` whose leaves were emptied by a delete
+    free_ids: Vec<u64>,
+    /// Every change made to the tree, logged next to the file contents
+    history: History,
+    /// Directory the contents of every file are saved in
+    dir: PathBuf,
+    /// Contents of replaced or deleted files, which the saved index may
+    /// still point to until it is written again
+    obsolete: Vec<PathBuf>,
+}
+
+/// Everything needed to rebuild a `Files` store after a restart, the
+/// contents themselves are read from the paths in each `PersistentFile`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedFiles {
+    file_id: u64,
+    free_ids: Vec<u64>,
+    files: Vec<SavedFile>,
+    top_hash: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedFile {
+    id: u64,
+    size: usize,
+    content_hash: Hash,
+    file: PersistentFile,
 }
 
 impl Files {
-    pub fn new() -> Self {
+    pub fn new(dir: PathBuf) -> Self {
         Files {
             file_id: 0,
             tree: MerkleTree::new(),
             file_map: HashMap::new(),
+            free_ids: Vec::new(),
+            history: History::new(dir.clone()),
+            dir,
+            obsolete: Vec::new(),
+        }
+    }
+
+    /// Rebuilds a store from its saved index, making sure the recomputed top
+    /// hash is the one that was saved
+    pub fn restore(saved: SavedFiles, dir: PathBuf) -> io::Result<Self> {
+        let mut files = Files::new(dir.clone());
+        files.file_id = saved.file_id;
+        files.free_ids = saved.free_ids;
+        files.history = History::load(dir, &saved.top_hash)?;
+
+        for SavedFile {
+            id,
+            size,
+            content_hash,
+            file,
+        } in saved.files
+        {
+            files.file_map.insert(file.name_hash.clone(), id);
+            *files.tree.get_file_mut(id) = Some(File::from_disk(file, size, content_hash));
+        }
+        // the tree never shrinks, even if the files in its last leaves were deleted
+        if let Some(last) = files.file_id.checked_sub(1) {
+            files.tree.grow_to(last);
+        }
+        files.tree.recompute_all_hashes();
+
+        if files.top_hash()[..] != saved.top_hash[..] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Recomputed top hash doesn't match the saved one",
+            ));
+        }
+        Ok(files)
+    }
+
+    pub fn saved(&self) -> SavedFiles {
+        let mut files = vec![];
+        for id in self.file_map.values() {
+            if let Some(file) = self.tree.get_file(*id) {
+                files.push(SavedFile {
+                    id: *id,
+                    size: file.size(),
+                    content_hash: *file.content_hash(),
+                    file: file.persistent(),
+                });
+            }
+        }
+
+        SavedFiles {
+            file_id: self.file_id,
+            free_ids: self.free_ids.clone(),
+            files,
+            top_hash: self.top_hash().to_vec(),
+        }
+    }
+
+    /// Name hashes can contain any character, so the contents are saved
+    /// under the hex encoded hash of the name hash. The hash of the contents
+    /// is appended so a new version never overwrites the contents the saved
+    /// index points to.
+    fn content_path(&self, name_hash: &str, content_hash: &Hash) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}",
+            to_hex(&sha256(name_hash.as_bytes())),
+            to_hex(content_hash)
+        ))
+    }
+
+    /// Removes the contents of replaced and deleted files, has to be called
+    /// only once the index no longer pointing to them has been saved
+    pub fn remove_obsolete(&mut self) {
+        for path in self.obsolete.drain(..) {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    println!("Couldn't remove saved contents ({}): {}", path.display(), e)
+                }
+                _ => (),
+            }
         }
     }
 
-    pub fn get_all_files(&self) -> Vec<(&String, &File)> {
+    pub fn get_all_files(&self) -> Vec<FileListEntry> {
         let mut list = vec![];
         for (k, v) in &self.file_map {
-            if let Some(file) = self.tree.get_file(*v) {
-                list.push((k, file));
+            if let Some(entry) = self.get_entry(k, *v) {
+                list.push(entry);
             }
         }
         list
     }
 
-    pub fn top_hash(&self) -> &Digest {
+    fn get_entry(&self, name_hash: &str, id: u64) -> Option<FileListEntry> {
+        let file = self.tree.get_file(id).as_ref()?;
+        Some(FileListEntry {
+            name_hash: name_hash.to_string(),
+            size: file.size(),
+            name: file.name(),
+            nonce: file.nonce(),
+            name_nonce: file.name_nonce(),
+            version: file.version(),
+            signature: file.signature(),
+            content_hash: file.content_hash().to_vec(),
+            signature_algorithm: file.signature_algorithm(),
+            signer: file.signer(),
+            kdf: file.kdf(),
+        })
+    }
+
+    pub fn top_hash(&self) -> &Hash {
         self.tree.top_hash()
     }
 
-    pub fn add_file(&mut self, data: NetworkFileData) {
-        let id = self.file_id;
-        self.file_id += 1;
-        self.file_map.insert(data.name_hash.clone(), id);
-        let node = self.tree.get_file_mut(id);
-        *node = Some(File::new(data));
+    pub fn tree_size(&self) -> u64 {
+        self.tree.size()
+    }
+
+    /// Version of the file stored under a name hash, if there is one
+    pub fn version(&self, name_hash: &str) -> Option<u64> {
+        let id = self.file_map.get(name_hash)?;
+        self.tree.get_file(*id).as_ref().map(|file| file.version())
+    }
+
+    fn leaf_hash(&self, id: u64) -> Hash {
+        match self.tree.get_file(id) {
+            Some(file) => file.leaf_hash(),
+            None => empty_leaf_hash(),
+        }
+    }
+
+    /// Records that the leaf for an ID changed, has to be called after the
+    /// hashes are recomputed
+    fn record_update(&mut self, id: u64, old_leaf: Hash) -> io::Result<()> {
+        let update = TreeUpdate {
+            old_leaf: old_leaf.to_vec(),
+            new_leaf: self.leaf_hash(id).to_vec(),
+            tree: self.tree.get_merkle_data_for_file(id),
+        };
+        self.history.append(&update)
+    }
+
+    /// Every update made since the tree had the given top hash and size, or
+    /// `None` if it never had them
+    pub fn get_consistency_proof(
+        &self,
+        info: &ConsistencyInfo,
+    ) -> io::Result<Option<ConsistencyProof>> {
+        let updates = match self.history.since(&info.top_hash, info.tree_size)? {
+            Some(x) => x,
+            // every tree starts out as a single empty leaf
+            None if info.top_hash[..] == empty_leaf_hash()[..] && info.tree_size == 1 => {
+                self.history.read_from(0)?
+            }
+            None => return Ok(None),
+        };
+
+        Ok(Some(ConsistencyProof { updates }))
+    }
+
+    /// Adds a file, replacing the contents of the leaf already holding a
+    /// file with the same name hash. Returns the ID of the leaf along with
+    /// the proof that it holds the new file.
+    pub fn add_file(&mut self, data: NetworkFileData) -> io::Result<(u64, types::MerkleData)> {
+        let name_hash = data.name_hash.clone();
+        let content_hash = sha256(&data.contents);
+        let path = self.content_path(&name_hash, &content_hash);
+        // the same contents pushed again are saved at the same path, which
+        // mustn't be removed when the index is saved
+        self.obsolete.retain(|x| *x != path);
+        let file = File::new(data, content_hash, path.clone());
+        file.save()?;
+
+        let id = match self.file_map.get(&name_hash) {
+            Some(id) => *id,
+            None => {
+                let id = self.free_ids.pop().unwrap_or_else(|| {
+                    self.file_id += 1;
+                    self.file_id - 1
+                });
+                self.file_map.insert(name_hash, id);
+                id
+            }
+        };
+
+        let old_leaf = self.leaf_hash(id);
+        if let Some(old) = self.tree.get_file_mut(id).replace(file) {
+            if old.path() != path {
+                self.obsolete.push(old.path().to_path_buf());
+            }
+        }
         self.tree.recompute_hashes();
+        self.record_update(id, old_leaf)?;
+        Ok((id, self.tree.get_merkle_data_for_file(id)))
     }
 
-    pub fn get_file(&mut self, info: FileInfo) -> Option<NetworkFileData> {
+    /// Reads a file, loading its contents from disk if they were evicted
+    pub fn get_file(&mut self, info: FileInfo) -> io::Result<Option<NetworkFileData>> {
         let id = match self.file_map.get(&info.name_hash) {
-            Some(x) => {
+            Some(x) => *x,
+            None => {
                 println!("Requested ID wasn't found (id: {})", info.name_hash);
-                *x
+                return Ok(None);
             }
-            None => return None,
         };
 
-        let f = self
-            .tree
-            .get_file(id)
-            .as_ref()
-            .map(|x| x.file_data().unwrap());
-
-        if f.is_none() {
-            println!("File was empty (id: {})", info.name_hash);
+        match self.tree.get_file(id) {
+            Some(file) => file.file_data().map(Some),
+            None => {
+                println!("File was empty (id: {})", info.name_hash);
+                Ok(None)
+            }
         }
-
-        f
     }
 
     pub fn get_merkle_data(&mut self, name: &str) -> Option<types::MerkleData> {
         let id = match self.file_map.get(name) {
-            Some(x) => {
+            Some(x) => *x,
+            None => {
                 println!("Requested ID wasn't found (id: {})", name);
-                *x
+                return None;
             }
-            None => return None,
         };
 
         Some(self.tree.get_merkle_data_for_file(id))
     }
+
+    pub fn size_of(&self, name_hash: &str) -> Option<usize> {
+        let id = self.file_map.get(name_hash)?;
+        self.tree.get_file(*id).as_ref().map(|x| x.size())
+    }
+
+    /// Drops the contents of a file from memory, they are read back from
+    /// disk the next time the file is pulled
+    pub fn evict(&self, name_hash: &str) {
+        if let Some(file) = self
+            .file_map
+            .get(name_hash)
+            .and_then(|id| self.tree.get_file(*id).as_ref())
+        {
+            file.evict();
+        }
+    }
+
+    /// Everything about a file except its contents, along with the proof
+    /// that it is part of the tree
+    pub fn get_metadata(&mut self, info: FileInfo) -> Option<(FileListEntry, types::MerkleData)> {
+        let id = *self.file_map.get(&info.name_hash)?;
+        let entry = self.get_entry(&info.name_hash, id)?;
+        Some((entry, self.tree.get_merkle_data_for_file(id)))
+    }
+
+    /// Removes a file from the tree, returning the proof that its leaf now
+    /// holds the empty hash
+    pub fn delete_file(&mut self, info: FileInfo) -> io::Result<Option<types::MerkleData>> {
+        let id = match self.file_map.remove(&info.name_hash) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let old_leaf = self.leaf_hash(id);
+
+        if let Some(file) = self.tree.get_file_mut(id).take() {
+            self.obsolete.push(file.path().to_path_buf());
+        }
+        self.tree.recompute_hashes();
+        self.free_ids.push(id);
+        self.record_update(id, old_leaf)?;
+
+        Ok(Some(self.tree.get_merkle_data_for_file(id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use types::{FileData, KdfParams, SignatureAlgorithm};
+
+    /// An empty directory only the calling test uses
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("krypto-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file(name_hash: &str, version: u64, contents: &[u8]) -> FileData {
+        FileData {
+            name_nonce: [1; 12],
+            name: name_hash.as_bytes().to_vec(),
+            name_hash: name_hash.to_string(),
+            nonce: [2; 12],
+            contents: contents.to_vec(),
+            version,
+            signature: vec![3],
+            signature_algorithm: SignatureAlgorithm::Ed25519,
+            signer: vec![4; 32],
+            kdf: KdfParams {
+                version: 1,
+                mem_cost: 19 * 1024,
+                time_cost: 2,
+                lanes: 1,
+            },
+        }
+    }
+
+    fn info(name_hash: &str) -> FileInfo {
+        FileInfo {
+            name_hash: name_hash.to_string(),
+        }
+    }
+
+    /// Goes through JSON like the index does
+    fn reload(saved: &SavedFiles, dir: &Path) -> Files {
+        let saved = rocket::serde::json::to_string(saved).unwrap();
+        Files::restore(
+            rocket::serde::json::from_str(&saved).unwrap(),
+            dir.to_path_buf(),
+        )
+        .unwrap()
+    }
+
+    fn contents(files: &mut Files, name_hash: &str) -> Option<Vec<u8>> {
+        files
+            .get_file(info(name_hash))
+            .unwrap()
+            .map(|file| file.contents)
+    }
+
+    #[test]
+    fn restores_saved_files() {
+        let dir = test_dir("restore");
+        let mut files = Files::new(dir.clone());
+        files.add_file(file("a", 1, b"first")).unwrap();
+        files.add_file(file("b", 1, b"second")).unwrap();
+        files.delete_file(info("a")).unwrap();
+        files.add_file(file("c", 1, b"third")).unwrap();
+
+        let mut restored = reload(&files.saved(), &dir);
+        assert_eq!(restored.top_hash(), files.top_hash());
+        assert_eq!(restored.tree_size(), files.tree_size());
+        assert_eq!(contents(&mut restored, "a"), None);
+        assert_eq!(contents(&mut restored, "b").unwrap(), b"second");
+        assert_eq!(contents(&mut restored, "c").unwrap(), b"third");
+        // the history of the restored tree still reaches back to the start
+        let proof = restored
+            .get_consistency_proof(&ConsistencyInfo {
+                top_hash: empty_leaf_hash().to_vec(),
+                tree_size: 1,
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(proof.updates.len(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_contents_outlive_the_saved_index() {
+        let dir = test_dir("replace");
+        let mut files = Files::new(dir.clone());
+        files.add_file(file("a", 1, b"old")).unwrap();
+        let saved = files.saved();
+        files.remove_obsolete();
+
+        // the server stops after the new contents were written, but
+        // before the index pointing to them was saved
+        files.add_file(file("a", 2, b"new")).unwrap();
+        let mut restored = reload(&saved, &dir);
+        assert_eq!(contents(&mut restored, "a").unwrap(), b"old");
+
+        // once the new index is saved the old contents go away
+        let saved = files.saved();
+        files.remove_obsolete();
+        let mut restored = reload(&saved, &dir);
+        assert_eq!(contents(&mut restored, "a").unwrap(), b"new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2); // contents and history
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn deleted_contents_outlive_the_saved_index() {
+        let dir = test_dir("delete");
+        let mut files = Files::new(dir.clone());
+        files.add_file(file("a", 1, b"kept")).unwrap();
+        let saved = files.saved();
+        files.remove_obsolete();
+
+        files.delete_file(info("a")).unwrap();
+        let mut restored = reload(&saved, &dir);
+        assert_eq!(contents(&mut restored, "a").unwrap(), b"kept");
+
+        files.remove_obsolete();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1); // only the history
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pushing_the_same_contents_again_keeps_them() {
+        let dir = test_dir("same");
+        let mut files = Files::new(dir.clone());
+        files.add_file(file("a", 1, b"same")).unwrap();
+        files.delete_file(info("a")).unwrap();
+        files.add_file(file("a", 2, b"same")).unwrap();
+
+        let saved = files.saved();
+        files.remove_obsolete();
+        let mut restored = reload(&saved, &dir);
+        assert_eq!(contents(&mut restored, "a").unwrap(), b"same");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evicted_contents_are_read_back_unchanged() {
+        let dir = test_dir("evict");
+        let mut files = Files::new(dir.clone());
+        files.add_file(file("a", 1, b"contents")).unwrap();
+        let path = files.saved().files[0].file.path.clone();
+        let written = fs::metadata(&path).unwrap().modified().unwrap();
+
+        assert_eq!(contents(&mut files, "a").unwrap(), b"contents");
+        files.evict("a");
+        // evicting doesn't write the contents again
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), written);
+        assert_eq!(contents(&mut files, "a").unwrap(), b"contents");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overwrites_keep_their_leaf() {
+        let dir = test_dir("overwrite");
+        let mut files = Files::new(dir.clone());
+        let (a, _) = files.add_file(file("a", 1, b"first")).unwrap();
+        let (b, _) = files.add_file(file("b", 1, b"other")).unwrap();
+        let (again, proof) = files.add_file(file("a", 2, b"second")).unwrap();
+
+        assert_eq!(again, a);
+        assert_ne!(b, a);
+        assert_eq!(proof.leaf_index(), Some(a));
+        assert_eq!(files.tree_size(), 2);
+        assert_eq!(contents(&mut files, "a").unwrap(), b"second");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn deleted_leaves_are_reused() {
+        let dir = test_dir("reuse");
+        let mut files = Files::new(dir.clone());
+        let (a, _) = files.add_file(file("a", 1, b"first")).unwrap();
+        let (b, _) = files.add_file(file("b", 1, b"second")).unwrap();
+        let proof = files.delete_file(info("a")).unwrap().unwrap();
+        assert_eq!(proof.leaf_index(), Some(a));
+
+        let (c, _) = files.add_file(file("c", 1, b"third")).unwrap();
+        assert_eq!(c, a);
+        assert_eq!(files.tree_size(), 2);
+
+        // freed IDs are saved with the index
+        files.delete_file(info("b")).unwrap();
+        let (d, _) = reload(&files.saved(), &dir)
+            .add_file(file("d", 1, b"fourth"))
+            .unwrap();
+        assert_eq!(d, b);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restores_trees_whose_last_leaves_are_empty() {
+        let dir = test_dir("shrink");
+        let mut files = Files::new(dir.clone());
+        for name_hash in ["a", "b", "c"] {
+            files
+                .add_file(file(name_hash, 1, name_hash.as_bytes()))
+                .unwrap();
+        }
+        files.delete_file(info("c")).unwrap();
+        files.delete_file(info("b")).unwrap();
+
+        let restored = reload(&files.saved(), &dir);
+        assert_eq!(restored.tree_size(), 4);
+        assert_eq!(restored.top_hash(), files.top_hash());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restore_rejects_a_wrong_top_hash() {
+        let dir = test_dir("top-hash");
+        let mut files = Files::new(dir.clone());
+        files.add_file(file("a", 1, b"contents")).unwrap();
+
+        let mut saved = files.saved();
+        saved.top_hash = empty_leaf_hash().to_vec();
+        let saved = rocket::serde::json::to_string(&saved).unwrap();
+        assert!(
+            Files::restore(rocket::serde::json::from_str(&saved).unwrap(), dir.clone()).is_err()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
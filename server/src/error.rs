@@ -0,0 +1,94 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+
+use super::account::AccountError;
+
+/// Everything a handler can fail with, each answered with its own status
+/// code and a short message in the body
+#[derive(Debug)]
+pub enum ServerError {
+    /// The requested file or top hash doesn't exist
+    NotFound(&'static str),
+    /// The request can't be acted on as sent
+    BadRequest(&'static str),
+    /// No session token, or one that has expired
+    Unauthorized,
+    /// Wrong password
+    Forbidden,
+    AccountTaken,
+    /// A push that would replace a file with an older or equal version
+    Conflict(&'static str),
+    IO(io::Error),
+    /// Hashing failed or a lock was poisoned by a panicking handler
+    Internal(&'static str),
+}
+
+impl ServerError {
+    pub fn status(&self) -> Status {
+        match self {
+            ServerError::NotFound(_) => Status::NotFound,
+            ServerError::BadRequest(_) => Status::BadRequest,
+            ServerError::Unauthorized => Status::Unauthorized,
+            ServerError::Forbidden => Status::Forbidden,
+            ServerError::AccountTaken => Status::ImATeapot,
+            ServerError::Conflict(_) => Status::Conflict,
+            ServerError::IO(_) | ServerError::Internal(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::NotFound(msg)
+            | ServerError::BadRequest(msg)
+            | ServerError::Conflict(msg) => {
+                write!(f, "{}", msg)
+            }
+            ServerError::Unauthorized => write!(f, "Missing or expired session"),
+            ServerError::Forbidden => write!(f, "Wrong password"),
+            ServerError::AccountTaken => write!(f, "Account already exists"),
+            // the details of internal errors are only logged, not sent
+            ServerError::IO(_) | ServerError::Internal(_) => write!(f, "Internal server error"),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ServerError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        match &self {
+            ServerError::IO(e) => println!("IO error handling {}: {}", request.uri(), e),
+            ServerError::Internal(msg) => println!("Error handling {}: {}", request.uri(), msg),
+            _ => {}
+        }
+        (self.status(), self.to_string()).respond_to(request)
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::IO(e)
+    }
+}
+
+impl From<AccountError> for ServerError {
+    fn from(e: AccountError) -> Self {
+        match e {
+            AccountError::AccountTaken => ServerError::AccountTaken,
+            AccountError::AccountNotFound => ServerError::NotFound("Account not found"),
+            AccountError::WrongPassword => ServerError::Forbidden,
+            AccountError::Hashing => ServerError::Internal("Hashing failed"),
+            AccountError::IO(e) => ServerError::IO(e),
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for ServerError {
+    fn from(_: PoisonError<T>) -> Self {
+        ServerError::Internal("Accounts lock was poisoned")
+    }
+}
@@ -7,9 +7,23 @@ use std::io::{self, prelude::*};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
-static SAVE_DIR_VAR: &'static str = "SERVER_SAVE_DIR";
+static SAVE_DIR_VAR: &str = "SERVER_SAVE_DIR";
 
-use types::FileData as MemoryFile;
+use types::{FileData as MemoryFile, KdfParams, SignatureAlgorithm};
+
+use crate::merkle_tree::Hash;
+
+/// The directory the server keeps its index and file contents in
+pub fn save_dir() -> PathBuf {
+    match env::var_os(SAVE_DIR_VAR) {
+        Some(dir) => Path::new(&dir).to_path_buf(),
+        None => env::temp_dir(),
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum RawFile {
@@ -19,19 +33,69 @@ pub enum RawFile {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct File {
-    inner: RefCell<RawFile>,
+    /// Boxed so tree leaves stay small whether or not they hold a file
+    inner: Box<RefCell<RawFile>>,
     size: usize,
+    /// SHA256 of the contents, computed once when the file is pushed
+    content_hash: Hash,
+    /// Where the contents are written when saved
+    path: PathBuf,
 }
 
 impl File {
-    pub fn new(data: MemoryFile) -> Self {
+    /// Wraps a pushed file, `content_hash` has to be the SHA256 of its contents
+    pub fn new(data: MemoryFile, content_hash: Hash, path: PathBuf) -> Self {
         let size = data.contents.len();
         Self {
-            inner: RefCell::new(RawFile::Memory(data)),
+            inner: Box::new(RefCell::new(RawFile::Memory(data))),
+            size,
+            content_hash,
+            path,
+        }
+    }
+
+    /// Recreates a file saved by an earlier run of the server
+    pub fn from_disk(pf: PersistentFile, size: usize, content_hash: Hash) -> Self {
+        Self {
+            path: pf.path.clone(),
+            inner: Box::new(RefCell::new(RawFile::Disk(pf))),
             size,
+            content_hash,
         }
     }
 
+    /// Writes the contents to disk, dropping them from memory
+    pub fn save(&self) -> io::Result<()> {
+        self.inner.borrow_mut().save(&self.path)
+    }
+
+    /// Drops the contents from memory. They were written when the file was
+    /// pushed and never change, so they are read back from there.
+    pub fn evict(&self) {
+        self.inner.borrow_mut().unload(&self.path)
+    }
+
+    /// Everything needed to find the file again after a restart. Only
+    /// meaningful once the file has been saved.
+    pub fn persistent(&self) -> PersistentFile {
+        match self.inner.borrow().deref() {
+            RawFile::Disk(pf) => pf.clone(),
+            RawFile::Memory(mf) => PersistentFile {
+                name_nonce: mf.name_nonce,
+                name: mf.name.clone(),
+                name_hash: mf.name_hash.clone(),
+                path: self.path.clone(),
+                nonce: mf.nonce,
+                version: mf.version,
+                signature: mf.signature.clone(),
+                signature_algorithm: mf.signature_algorithm,
+                signer: mf.signer.clone(),
+                kdf: mf.kdf,
+            },
+        }
+    }
+
+    /// Loads the file into memory if it was saved to disk
     pub fn file_data(&self) -> io::Result<MemoryFile> {
         let mut borrow = self.inner.borrow_mut();
         borrow.load()?;
@@ -41,16 +105,68 @@ impl File {
         })
     }
 
-    pub fn file_data_mut(&mut self) -> &mut MemoryFile {
-        todo!()
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn size(&self) -> usize {
         self.size
     }
 
-    pub fn contents(&self) -> io::Result<Vec<u8>> {
-        Ok(self.inner.borrow_mut().load()?.contents.clone())
+    pub fn content_hash(&self) -> &Hash {
+        &self.content_hash
+    }
+
+    /// Hash of the merkle leaf holding this file, using the stored content
+    /// digest so the contents never have to be read back from disk
+    pub fn leaf_hash(&self) -> Hash {
+        types::merkle::leaf_hash(
+            &self.name_hash(),
+            &self.name_nonce(),
+            &self.name(),
+            &self.nonce(),
+            self.version(),
+            &self.signature(),
+            self.signature_algorithm(),
+            &self.signer(),
+            &self.content_hash,
+            &self.kdf(),
+        )
+    }
+
+    pub fn version(&self) -> u64 {
+        match self.inner.borrow().deref() {
+            RawFile::Disk(pf) => pf.version,
+            RawFile::Memory(mf) => mf.version,
+        }
+    }
+
+    pub fn signature_algorithm(&self) -> SignatureAlgorithm {
+        match self.inner.borrow().deref() {
+            RawFile::Disk(pf) => pf.signature_algorithm,
+            RawFile::Memory(mf) => mf.signature_algorithm,
+        }
+    }
+
+    pub fn signer(&self) -> Vec<u8> {
+        match self.inner.borrow().deref() {
+            RawFile::Disk(pf) => pf.signer.clone(),
+            RawFile::Memory(mf) => mf.signer.clone(),
+        }
+    }
+
+    pub fn kdf(&self) -> KdfParams {
+        match self.inner.borrow().deref() {
+            RawFile::Disk(pf) => pf.kdf,
+            RawFile::Memory(mf) => mf.kdf,
+        }
+    }
+
+    pub fn signature(&self) -> Vec<u8> {
+        match self.inner.borrow().deref() {
+            RawFile::Disk(pf) => pf.signature.clone(),
+            RawFile::Memory(mf) => mf.signature.clone(),
+        }
     }
 
     pub fn name_hash(&self) -> String {
@@ -89,7 +205,11 @@ pub struct PersistentFile {
     pub name_hash: String,
     pub path: PathBuf,
     pub nonce: [u8; 12],
+    pub version: u64,
     pub signature: Vec<u8>,
+    pub signature_algorithm: SignatureAlgorithm,
+    pub signer: Vec<u8>,
+    pub kdf: KdfParams,
 }
 
 impl RawFile {
@@ -105,7 +225,11 @@ impl RawFile {
             name_hash,
             path,
             nonce,
+            version,
             signature,
+            signature_algorithm,
+            signer,
+            kdf,
         } = pf.clone();
 
         let mut buf = Vec::new();
@@ -117,8 +241,12 @@ impl RawFile {
             name,
             name_hash,
             nonce,
+            version,
             signature,
             contents: buf,
+            signature_algorithm,
+            signer,
+            kdf,
         });
 
         match self {
@@ -126,30 +254,44 @@ impl RawFile {
             _ => unreachable!(),
         }
     }
-    fn save(&mut self) -> io::Result<()> {
+
+    fn save(&mut self, path: &Path) -> io::Result<()> {
         let mf = match self {
             RawFile::Disk(_) => return Ok(()),
             RawFile::Memory(mf) => mf,
         };
 
-        let mut path = match env::var_os(SAVE_DIR_VAR) {
-            Some(dir) => Path::new(&dir).to_path_buf(),
-            None => env::temp_dir(),
-        };
+        // written next to the path first, so contents the same file already
+        // had are never left half overwritten
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(&mf.contents[..])?;
+        fs::rename(&tmp, path)?;
 
-        path.push(&mf.name_hash);
-
-        let contents = std::mem::replace(&mut mf.contents, vec![]);
+        self.unload(path);
+        Ok(())
+    }
 
-        let mut f = fs::File::create(&path)?;
-        f.write_all(&contents[..])?;
+    /// Switches to the contents saved at `path`, which have to be the same
+    /// as the ones in memory
+    fn unload(&mut self, path: &Path) {
+        let mf = match self {
+            RawFile::Disk(_) => return,
+            RawFile::Memory(mf) => mf,
+        };
+        mf.contents = vec![];
 
         let MemoryFile {
             name_nonce,
             name,
             name_hash,
             nonce,
+            version,
             signature,
+            signature_algorithm,
+            signer,
+            kdf,
             ..
         } = mf.clone();
 
@@ -157,11 +299,13 @@ impl RawFile {
             name_nonce,
             name,
             name_hash,
-            path,
+            path: path.to_path_buf(),
             nonce,
+            version,
             signature,
+            signature_algorithm,
+            signer,
+            kdf,
         });
-
-        Ok(())
     }
 }
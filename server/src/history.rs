@@ -0,0 +1,260 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use types::TreeUpdate;
+
+use super::merkle_tree::Hash;
+
+/// Name of the log in an account's directory every tree update is appended to
+static HISTORY_FILE: &str = "history.log";
+
+/// Every change made to a tree, so clients can be shown how it got from a
+/// top hash they trust to the current one. The updates are appended to a
+/// log with one JSON object per line, only their top hashes are kept in
+/// memory.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    /// Top hash and size of the tree after every update, along with where
+    /// the update starts in the log
+    entries: Vec<(Hash, u64, u64)>,
+    /// Length of the log, the next update is written there
+    len: u64,
+}
+
+impl History {
+    pub fn new(dir: PathBuf) -> Self {
+        History {
+            path: dir.join(HISTORY_FILE),
+            entries: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Reads the log in `dir`, dropping every update made after the tree had
+    /// `top_hash`. Those were logged but never made it into a saved index,
+    /// so the tree they lead to doesn't exist anymore.
+    pub fn load(dir: PathBuf, top_hash: &[u8]) -> io::Result<Self> {
+        let mut history = History::new(dir);
+        let file = match fs::File::open(&history.path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(e),
+        };
+
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            // a line without a newline was cut short while being written
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            let update: TreeUpdate = rocket::serde::json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            history.push(&update, read as u64)?;
+        }
+
+        let keep = history
+            .entries
+            .iter()
+            .rposition(|(hash, _, _)| hash[..] == top_hash[..])
+            .map_or(0, |i| i + 1);
+        if keep < history.entries.len() || history.len < reader.get_ref().metadata()?.len() {
+            println!(
+                "Dropping tree updates that were never saved ({})",
+                history.path.display()
+            );
+            history.truncate(keep)?;
+        }
+
+        Ok(history)
+    }
+
+    /// Indexes an update written to the log with the given length
+    fn push(&mut self, update: &TreeUpdate, len: u64) -> io::Result<()> {
+        let hash = update
+            .tree
+            .top_hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Malformed top hash"))?;
+        let size = update
+            .tree
+            .tree_size()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed tree size"))?;
+        self.entries.push((hash, size, self.len));
+        self.len += len;
+        Ok(())
+    }
+
+    /// Keeps only the first `count` updates, in memory and in the log
+    fn truncate(&mut self, count: usize) -> io::Result<()> {
+        if let Some(&(_, _, offset)) = self.entries.get(count) {
+            self.len = offset;
+        }
+        self.entries.truncate(count);
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)?
+            .set_len(self.len)
+    }
+
+    pub fn append(&mut self, update: &TreeUpdate) -> io::Result<()> {
+        let mut line = rocket::serde::json::to_string(update).map_err(io::Error::other)?;
+        line.push('\n');
+
+        // written at the end of the last complete update rather than
+        // appended, so a write that failed halfway is overwritten
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.path)?;
+        file.seek(SeekFrom::Start(self.len))?;
+        file.write_all(line.as_bytes())?;
+        self.push(update, line.len() as u64)
+    }
+
+    /// Every update made since the tree had the given top hash and size, or
+    /// `None` if it never had them
+    pub fn since(&self, top_hash: &[u8], tree_size: u64) -> io::Result<Option<Vec<TreeUpdate>>> {
+        let start = match self
+            .entries
+            .iter()
+            .rposition(|(hash, size, _)| hash[..] == top_hash[..] && *size == tree_size)
+        {
+            Some(i) => i + 1,
+            None => return Ok(None),
+        };
+        self.read_from(start).map(Some)
+    }
+
+    /// Reads every update from the given one to the end of the log
+    pub fn read_from(&self, start: usize) -> io::Result<Vec<TreeUpdate>> {
+        let offset = match self.entries.get(start) {
+            Some(&(_, _, offset)) => offset,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut file = fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut updates = Vec::with_capacity(self.entries.len() - start);
+        for line in BufReader::new(file).take(self.len - offset).lines() {
+            let update = rocket::serde::json::from_str(&line?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            updates.push(update);
+        }
+        Ok(updates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::{MerkleData, Side};
+
+    /// An empty directory only the calling test uses
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("krypto-history-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An update leading to a tree with top hash `[n; 32]` and two leaves
+    fn update(n: u8) -> TreeUpdate {
+        TreeUpdate {
+            old_leaf: vec![n; 32],
+            new_leaf: vec![n + 1; 32],
+            tree: MerkleData {
+                top_hash: vec![n; 32],
+                hashes: vec![(Side::Right, vec![0; 32])],
+            },
+        }
+    }
+
+    fn top_hashes(updates: &[TreeUpdate]) -> Vec<u8> {
+        updates.iter().map(|x| x.tree.top_hash[0]).collect()
+    }
+
+    #[test]
+    fn updates_since_a_top_hash() {
+        let dir = test_dir("since");
+        let mut history = History::new(dir.clone());
+        for n in 1..=3 {
+            history.append(&update(n)).unwrap();
+        }
+
+        let since = history.since(&[1; 32], 2).unwrap().unwrap();
+        assert_eq!(top_hashes(&since), vec![2, 3]);
+        assert!(history.since(&[3; 32], 2).unwrap().unwrap().is_empty());
+        // the size has to match along with the top hash
+        assert!(history.since(&[1; 32], 4).unwrap().is_none());
+        assert!(history.since(&[9; 32], 2).unwrap().is_none());
+        assert_eq!(top_hashes(&history.read_from(0).unwrap()), vec![1, 2, 3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loads_every_saved_update() {
+        let dir = test_dir("load");
+        let mut history = History::new(dir.clone());
+        for n in 1..=3 {
+            history.append(&update(n)).unwrap();
+        }
+
+        let loaded = History::load(dir.clone(), &[3; 32]).unwrap();
+        assert_eq!(top_hashes(&loaded.read_from(0).unwrap()), vec![1, 2, 3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_updates_after_the_saved_top_hash() {
+        let dir = test_dir("unsaved");
+        let mut history = History::new(dir.clone());
+        for n in 1..=3 {
+            history.append(&update(n)).unwrap();
+        }
+
+        // the index was last saved when the tree had the second top hash
+        let mut loaded = History::load(dir.clone(), &[2; 32]).unwrap();
+        assert_eq!(top_hashes(&loaded.read_from(0).unwrap()), vec![1, 2]);
+        loaded.append(&update(4)).unwrap();
+
+        let loaded = History::load(dir.clone(), &[4; 32]).unwrap();
+        assert_eq!(top_hashes(&loaded.read_from(0).unwrap()), vec![1, 2, 4]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_a_partly_written_update() {
+        let dir = test_dir("partial");
+        let mut history = History::new(dir.clone());
+        history.append(&update(1)).unwrap();
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(HISTORY_FILE))
+            .unwrap();
+        log.write_all(b"{\"old_leaf\":[").unwrap();
+
+        let mut loaded = History::load(dir.clone(), &[1; 32]).unwrap();
+        assert_eq!(top_hashes(&loaded.read_from(0).unwrap()), vec![1]);
+        loaded.append(&update(2)).unwrap();
+
+        let loaded = History::load(dir.clone(), &[2; 32]).unwrap();
+        assert_eq!(top_hashes(&loaded.read_from(0).unwrap()), vec![1, 2]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_log_is_an_empty_history() {
+        let dir = test_dir("missing");
+        let history = History::load(dir.clone(), &[1; 32]).unwrap();
+        assert!(history.read_from(0).unwrap().is_empty());
+        assert!(history.since(&[1; 32], 2).unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,68 +1,280 @@
 #[macro_use]
 extern crate rocket;
 
-use rocket::data::FromData;
-use rocket::form::Form;
-use rocket::response;
-use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::serde::json::Json;
 use rocket::State;
 
 use std::sync::Mutex;
 
-use types::{FileData, FileInfo, FileList, FileListEntry, MerkleData};
+use types::{
+    ConsistencyInfo, ConsistencyProof, CreateInfo, FileData, FileInfo, FileList, FileListEntry,
+    LoginInfo, MerkleData, PublicKeyInfo, PushResult, Session, SignedTreeHead,
+};
 
+use account::{hash_password, verify_password, Accounts, User};
+use error::ServerError;
+use signing::ServerKey;
+
+mod account;
+mod cache;
 mod data;
+mod error;
 mod file;
+mod history;
 mod merkle_tree;
+mod signing;
+
+/// Argon2 refuses shorter salts, so a client sending one couldn't derive its key
+const MIN_SALT_LEN: usize = 8;
 
 #[get("/")]
 fn index() -> &'static str {
     "Hello, world!"
 }
 
+// 200 = success
+// 400 = empty username or too short key salt
+// 418 = account already exists
+#[post("/create", format = "json", data = "<info>")]
+fn create(
+    accounts: &State<Mutex<Accounts>>,
+    info: Json<CreateInfo>,
+) -> Result<Json<Session>, ServerError> {
+    if info.name.is_empty() {
+        return Err(ServerError::BadRequest("Username can't be empty"));
+    }
+    if info.key_derivation.salt.len() < MIN_SALT_LEN {
+        return Err(ServerError::BadRequest("Key salt is too short"));
+    }
+
+    let password_hash = hash_password(&info.password)?;
+
+    let mut accounts = accounts.lock()?;
+    let name = info.name.clone();
+    let key_derivation = info.key_derivation.clone();
+    accounts.create(info.into_inner(), password_hash)?;
+    let token = accounts.start_session(&name)?;
+    Ok(Json(Session {
+        token,
+        key_derivation,
+    }))
+}
+
+// 200 = success
+// 403 = wrong password
+// 404 = account not found
+#[post("/login", format = "json", data = "<info>")]
+fn login(
+    accounts: &State<Mutex<Accounts>>,
+    info: Json<LoginInfo>,
+) -> Result<Json<Session>, ServerError> {
+    // the lock isn't held while the password is checked, so a slow login
+    // doesn't hold up every other request
+    let (password_hash, key_derivation) = accounts.lock()?.credentials(&info.name)?;
+    verify_password(&password_hash, &info.password)?;
+    let token = accounts.lock()?.start_session(&info.name)?;
+    Ok(Json(Session {
+        token,
+        key_derivation,
+    }))
+}
+
+// 200 = success
+// 400 = empty name hash or malformed signer
+// 409 = the stored file has the same or a newer version
 #[post("/push", format = "json", data = "<file>")]
-fn push(db: &State<Mutex<data::Files>>, file: Json<FileData>) {
-    db.lock().unwrap().add_file(file.into_inner());
+fn push(
+    accounts: &State<Mutex<Accounts>>,
+    key: &State<ServerKey>,
+    user: User,
+    file: Json<FileData>,
+) -> Result<Json<PushResult>, ServerError> {
+    let file = file.into_inner();
+    if file.name_hash.is_empty() {
+        return Err(ServerError::BadRequest("Name hash can't be empty"));
+    }
+    if file.signer.len() != 32 {
+        return Err(ServerError::BadRequest(
+            "Signer has to be the SHA256 fingerprint of a public key",
+        ));
+    }
+
+    let mut accounts = accounts.lock()?;
+    if let Some(version) = accounts.files(&user).version(&file.name_hash) {
+        if version >= file.version {
+            return Err(ServerError::Conflict(
+                "The stored file has the same or a newer version",
+            ));
+        }
+    }
+    accounts.forget(&user, &file.name_hash);
+    let (leaf_index, tree) = accounts.files_mut(&user).add_file(file)?;
+    accounts.save()?;
+
+    Ok(Json(PushResult {
+        leaf_index,
+        tree,
+        head: key.sign_head(&user.0, accounts.files(&user)),
+    }))
 }
 
+// 200 = success
+// 404 = file not found
 #[get("/pull", format = "json", data = "<info>")]
 fn pull(
-    db: &State<Mutex<data::Files>>,
+    accounts: &State<Mutex<Accounts>>,
+    key: &State<ServerKey>,
+    user: User,
     info: Json<FileInfo>,
-) -> Json<Option<(FileData, MerkleData)>> {
-    let mut lock = db.lock().unwrap();
+) -> Result<Json<(FileData, MerkleData, SignedTreeHead)>, ServerError> {
+    let mut accounts = accounts.lock()?;
+    let lock = accounts.files_mut(&user);
     let info = info.into_inner();
-    let file = lock.get_file(info.clone());
-    Json(file.and_then(|x| lock.get_merkle_data(&info.name_hash).map(|data| (x, data))))
+    let file = lock
+        .get_file(info.clone())?
+        .ok_or(ServerError::NotFound("File not found"))?;
+    let data = lock
+        .get_merkle_data(&info.name_hash)
+        .ok_or(ServerError::NotFound("File not found"))?;
+    let head = key.sign_head(&user.0, lock);
+    accounts.used(&user, &info.name_hash);
+    Ok(Json((file, data, head)))
+}
+
+// 200 = success
+// 404 = file not found
+#[get("/get", format = "json", data = "<info>")]
+fn get(
+    accounts: &State<Mutex<Accounts>>,
+    key: &State<ServerKey>,
+    user: User,
+    info: Json<FileInfo>,
+) -> Result<Json<(FileListEntry, MerkleData, SignedTreeHead)>, ServerError> {
+    let mut accounts = accounts.lock()?;
+    let files = accounts.files_mut(&user);
+    let (entry, data) = files
+        .get_metadata(info.into_inner())
+        .ok_or(ServerError::NotFound("File not found"))?;
+    let head = key.sign_head(&user.0, files);
+    Ok(Json((entry, data, head)))
+}
+
+// 200 = success
+// 404 = file not found
+#[post("/delete", format = "json", data = "<info>")]
+fn delete(
+    accounts: &State<Mutex<Accounts>>,
+    key: &State<ServerKey>,
+    user: User,
+    info: Json<FileInfo>,
+) -> Result<Json<(MerkleData, SignedTreeHead)>, ServerError> {
+    let mut accounts = accounts.lock()?;
+    accounts.forget(&user, &info.name_hash);
+    let data = accounts
+        .files_mut(&user)
+        .delete_file(info.into_inner())?
+        .ok_or(ServerError::NotFound("File not found"))?;
+    accounts.save()?;
+    let head = key.sign_head(&user.0, accounts.files(&user));
+    Ok(Json((data, head)))
 }
 
 #[get("/list")]
-fn list(db: &State<Mutex<data::Files>>) -> Json<FileList> {
-    let files = db.lock().unwrap();
-
-    let mut list = FileList {
-        top_hash: files.top_hash().as_ref().to_vec(),
-        list: vec![],
-    };
-
-    for (name, file) in files.get_all_files() {
-        list.list.push(FileListEntry {
-            name_hash: name.to_string(),
-            size: file.size(),
-            name: file.name(),
-            nonce: file.nonce(),
-            name_nonce: file.name_nonce(),
-        })
+fn list(
+    accounts: &State<Mutex<Accounts>>,
+    key: &State<ServerKey>,
+    user: User,
+) -> Result<Json<FileList>, ServerError> {
+    let accounts = accounts.lock()?;
+    let files = accounts.files(&user);
+
+    Ok(Json(FileList {
+        head: key.sign_head(&user.0, files),
+        list: files.get_all_files(),
+    }))
+}
+
+// 200 = success
+// 400 = empty public key
+#[post("/keys", format = "json", data = "<info>")]
+fn register_key(
+    accounts: &State<Mutex<Accounts>>,
+    user: User,
+    info: Json<PublicKeyInfo>,
+) -> Result<(), ServerError> {
+    if info.public_key.is_empty() {
+        return Err(ServerError::BadRequest("Public key can't be empty"));
     }
 
-    Json(list)
+    let mut accounts = accounts.lock()?;
+    accounts.register_key(&user, info.into_inner())?;
+    Ok(())
+}
+
+/// Every public key registered with the account, for clients to verify
+/// files signed with keys they haven't seen yet
+#[get("/keys")]
+fn public_keys(
+    accounts: &State<Mutex<Accounts>>,
+    user: User,
+) -> Result<Json<Vec<PublicKeyInfo>>, ServerError> {
+    let accounts = accounts.lock()?;
+    Ok(Json(accounts.public_keys(&user).to_vec()))
+}
+
+/// The public key tree heads are signed with, for clients to pin
+#[get("/key")]
+fn server_key(key: &State<ServerKey>) -> Json<Vec<u8>> {
+    Json(key.public_key())
+}
+
+// 200 = success
+// 404 = the tree never had the given top hash
+#[get("/consistency", format = "json", data = "<info>")]
+fn consistency(
+    accounts: &State<Mutex<Accounts>>,
+    user: User,
+    info: Json<ConsistencyInfo>,
+) -> Result<Json<ConsistencyProof>, ServerError> {
+    let accounts = accounts.lock()?;
+    accounts
+        .files(&user)
+        .get_consistency_proof(&info)?
+        .map(Json)
+        .ok_or(ServerError::NotFound("Unknown top hash"))
+}
+
+/// Bodies that can't be parsed are bad input like any other
+#[catch(422)]
+fn unprocessable() -> ServerError {
+    ServerError::BadRequest("Malformed request body")
 }
 
 #[launch]
 fn launch() -> _ {
-    let file_db = data::Files::new();
+    let accounts = Accounts::load(file::save_dir(), cache::ContentCache::from_env())
+        .expect("Couldn't load saved accounts");
+    let key = ServerKey::load(&file::save_dir()).expect("Couldn't load server key");
 
     rocket::build()
-        .mount("/", routes![index, push, pull, list])
-        .manage(Mutex::new(file_db))
+        .mount(
+            "/",
+            routes![
+                index,
+                create,
+                login,
+                push,
+                pull,
+                get,
+                delete,
+                list,
+                consistency,
+                server_key,
+                register_key,
+                public_keys
+            ],
+        )
+        .register("/", catchers![unprocessable])
+        .manage(Mutex::new(accounts))
+        .manage(key)
 }
@@ -1,102 +1,162 @@
-const MAX_DEPTH: u64 = 8;
-const MAX_FILE_ID: u64 = 1 << MAX_DEPTH;
-
-use serde::{Deserialize, Serialize};
-
 use crate::file::File;
-use ring::digest::{digest, Digest, SHA256};
+use types::merkle::{empty_leaf_hash, node_hash};
 use types::Side;
 
+pub type Hash = [u8; 32];
+
+/// A binary merkle tree with a file in every leaf. File IDs are routed from
+/// the most significant bit down, so the tree can double in size by making
+/// the old root the left half of a new root, without moving any files.
 #[derive(Debug)]
 pub struct MerkleTree {
     root: Node,
+    /// Number of branches between the root and a leaf, the tree has room for
+    /// `1 << depth` files
+    depth: u32,
+    /// The hash of a subtree without any files, indexed by its height
+    empty_hashes: Vec<Hash>,
 }
 
 impl MerkleTree {
     pub fn new() -> Self {
+        let empty_leaf = empty_leaf_hash();
         Self {
-            root: Self::rec_create_nodes(MAX_DEPTH, 0),
+            root: Node::Empty { hash: empty_leaf },
+            depth: 0,
+            empty_hashes: vec![empty_leaf],
         }
     }
 
-    pub fn top_hash(&self) -> &Digest {
+    pub fn top_hash(&self) -> &Hash {
         self.root.digest()
     }
 
-    fn rec_create_nodes(depth: u64, id: u64) -> Node {
-        if depth == 0 {
-            Node::Leaf {
-                id: id,
-                hash: digest(&SHA256, &[]),
-                data: None,
-                dirty: false,
-            }
-        } else {
-            let left = Box::new(Self::rec_create_nodes(depth - 1, id << 1));
-            let right = Box::new(Self::rec_create_nodes(depth - 1, (id << 1) | 1));
+    /// Number of leaves in the tree
+    pub fn size(&self) -> u64 {
+        1 << self.depth
+    }
 
-            let mut combined = left.hash_bytes().to_vec();
-            combined.extend_from_slice(right.hash_bytes());
+    /// Doubles the number of leaves, placing the current tree to the left
+    fn grow(&mut self) {
+        let empty = self.empty_hashes[self.depth as usize];
+        self.empty_hashes.push(node_hash(&empty, &empty));
 
-            Node::Branch {
-                hash: digest(&SHA256, &combined),
-                left,
-                right,
-                dirty: false,
-            }
-        }
+        let old_root = std::mem::replace(&mut self.root, Node::Empty { hash: empty });
+        self.root = Node::Branch {
+            hash: node_hash(old_root.hash_bytes(), &empty),
+            left: Box::new(old_root),
+            right: Box::new(Node::Empty { hash: empty }),
+            dirty: true,
+        };
+        self.depth += 1;
     }
 
-    pub fn get_root_hash(&self) -> &[u8] {
-        self.root.hash_bytes()
+    pub fn get_file(&self, id: u64) -> &Option<File> {
+        if id >> self.depth != 0 {
+            return &None;
+        }
+        match self.root.get_leaf(id, self.depth) {
+            Some(Node::Leaf { data, .. }) => data,
+            _ => &None,
+        }
     }
 
-    pub fn get_file(&self, id: u64) -> &Option<File> {
-        self.root.get_file(id)
+    /// Retreive a mutable handle to a file, growing the tree if the ID
+    /// doesn't fit yet
+    /// Grows the tree until it has a leaf for the given ID
+    pub fn grow_to(&mut self, id: u64) {
+        while self.depth < u64::BITS && id >> self.depth != 0 {
+            self.grow();
+        }
     }
 
     pub fn get_file_mut(&mut self, id: u64) -> &mut Option<File> {
-        self.root.get_file_mut(id)
+        self.grow_to(id);
+        self.root.get_file_mut(id, self.depth, &self.empty_hashes)
     }
 
     pub fn recompute_hashes(&mut self) {
         self.root.recompute_hash_if_dirty()
     }
 
+    pub fn recompute_all_hashes(&mut self) {
+        self.root.recompute_hash_full()
+    }
+
     pub fn get_merkle_data_for_file(&mut self, id: u64) -> types::MerkleData {
         let mut v = Vec::new();
-        self.root.get_hashes_for_file(id, &mut v);
+        self.root
+            .get_hashes_for_file(id, self.depth, &self.empty_hashes, &mut v);
+        // the hashes are collected from the root down, but are applied from the leaf up
         let v = v
             .into_iter()
-            .map(|(side, dig)| (side, dig.as_ref().to_vec()))
+            .rev()
+            .map(|(side, hash)| (side, hash.to_vec()))
             .collect::<Vec<_>>();
         types::MerkleData {
-            top_hash: self.root.digest().as_ref().to_vec(),
+            top_hash: self.root.digest().to_vec(),
             hashes: v,
         }
     }
 }
 
+fn hash_leaf(data: &Option<File>) -> Hash {
+    match data {
+        Some(file) => file.leaf_hash(),
+        None => empty_leaf_hash(),
+    }
+}
+
 #[derive(Debug)]
 pub enum Node {
     Leaf {
-        id: u64,
         data: Option<File>,
-        hash: Digest,
+        hash: Hash,
         dirty: bool,
     },
     Branch {
-        hash: Digest,
+        hash: Hash,
         left: Box<Node>,
         right: Box<Node>,
         dirty: bool,
     },
+    /// A subtree without any files, only expanded once a file is added to it
+    Empty { hash: Hash },
 }
 
 impl Node {
+    /// Replaces an empty subtree of the given height with a branch of two
+    /// empty halves, or an empty leaf at the bottom of the tree
+    fn expand(height: u32, empty_hashes: &[Hash]) -> Node {
+        if height == 0 {
+            Node::Leaf {
+                data: None,
+                hash: empty_hashes[0],
+                dirty: false,
+            }
+        } else {
+            let half = empty_hashes[height as usize - 1];
+            Node::Branch {
+                hash: empty_hashes[height as usize],
+                left: Box::new(Node::Empty { hash: half }),
+                right: Box::new(Node::Empty { hash: half }),
+                dirty: false,
+            }
+        }
+    }
+
     /// Retreive a mutable handle to a file from the merkle tree using
     /// the ID, marking every branch as dirty along the way
-    pub fn get_file_mut(&mut self, id: u64) -> &mut Option<File> {
+    pub fn get_file_mut(
+        &mut self,
+        id: u64,
+        height: u32,
+        empty_hashes: &[Hash],
+    ) -> &mut Option<File> {
+        if let Node::Empty { .. } = self {
+            *self = Node::expand(height, empty_hashes);
+        }
+
         match self {
             Node::Branch {
                 left,
@@ -105,12 +165,12 @@ impl Node {
                 ..
             } => {
                 *dirty = true;
-                match id & 1 {
+                match (id >> (height - 1)) & 1 {
                     0 => left,
                     1 => right,
                     _ => unreachable!(),
                 }
-                .get_file_mut(id >> 1)
+                .get_file_mut(id, height - 1, empty_hashes)
             }
             Node::Leaf {
                 ref mut data,
@@ -120,124 +180,110 @@ impl Node {
                 *dirty = true;
                 data
             }
+            Node::Empty { .. } => unreachable!(),
         }
     }
 
-    pub fn get_file(&self, id: u64) -> &Option<File> {
+    /// Finds the leaf for an ID, or `None` if it is in an empty subtree
+    pub fn get_leaf(&self, id: u64, height: u32) -> Option<&Node> {
         match self {
-            Node::Branch { left, right, .. } => match id & 1 {
+            Node::Branch { left, right, .. } => match (id >> (height - 1)) & 1 {
                 0 => left,
                 1 => right,
                 _ => unreachable!(),
             }
-            .get_file(id >> 1),
-            Node::Leaf { data, .. } => data,
+            .get_leaf(id, height - 1),
+            Node::Leaf { .. } => Some(self),
+            Node::Empty { .. } => None,
         }
     }
 
     /// Recomputes the hash for all nodes marked as dirty
     pub fn recompute_hash_if_dirty(&mut self) {
-        if *self.dirty_mut() {
-            *self.dirty_mut() = false;
-
-            match self {
-                Node::Leaf { hash, data, .. } => {
-                    *hash = digest(
-                        &SHA256,
-                        &data
-                            .as_ref()
-                            .map(|x| x.contents().unwrap())
-                            .unwrap_or(vec![]),
-                    );
-                }
-                Node::Branch {
-                    hash, left, right, ..
-                } => {
-                    left.recompute_hash_if_dirty();
-                    right.recompute_hash_if_dirty();
-                    let mut concat = left.hash_bytes().to_vec();
-                    concat.extend_from_slice(&right.hash_bytes());
-                    *hash = digest(&SHA256, &concat[..]);
-                }
-            }
-        }
-    }
-
-    pub fn dirty_mut(&mut self) -> &mut bool {
         match self {
-            Node::Leaf { dirty, .. } => dirty,
-            Node::Branch { dirty, .. } => dirty,
+            Node::Leaf { hash, data, dirty } if *dirty => {
+                *hash = hash_leaf(data);
+                *dirty = false;
+            }
+            Node::Branch {
+                hash,
+                left,
+                right,
+                dirty,
+            } if *dirty => {
+                left.recompute_hash_if_dirty();
+                right.recompute_hash_if_dirty();
+                *hash = node_hash(left.hash_bytes(), right.hash_bytes());
+                *dirty = false;
+            }
+            _ => {}
         }
     }
 
     /// Unconditionally recomputes the hashes for all nodes
     pub fn recompute_hash_full(&mut self) {
         match self {
-            Node::Leaf { hash, data, .. } => {
-                if let Some(file) = data {
-                    *hash = digest(&SHA256, &file.contents().unwrap());
-                } else {
-                    *hash = digest(&SHA256, &[]);
-                }
+            Node::Leaf { hash, data, dirty } => {
+                *hash = hash_leaf(data);
+                *dirty = false;
             }
             Node::Branch {
-                hash, left, right, ..
+                hash,
+                left,
+                right,
+                dirty,
             } => {
                 left.recompute_hash_full();
                 right.recompute_hash_full();
-                let mut concat = left.hash_bytes().to_vec();
-                concat.extend_from_slice(&right.hash_bytes());
-                *hash = digest(&SHA256, &concat[..]);
+                *hash = node_hash(left.hash_bytes(), right.hash_bytes());
+                *dirty = false;
             }
+            Node::Empty { .. } => {}
         }
-        *self.dirty_mut() = false;
     }
 
-    pub fn digest(&self) -> &Digest {
+    pub fn digest(&self) -> &Hash {
         match self {
             Node::Leaf { hash, .. } => hash,
             Node::Branch { hash, .. } => hash,
+            Node::Empty { hash } => hash,
         }
     }
 
     pub fn hash_bytes(&self) -> &[u8] {
-        match self {
-            Node::Leaf { hash, .. } => hash,
-            Node::Branch { hash, .. } => hash,
-        }
-        .as_ref()
+        self.digest()
     }
 
-    pub fn get_hashes_for_file(&self, id: u64, v: &mut Vec<(Side, Digest)>) {
+    pub fn get_hashes_for_file(
+        &self,
+        id: u64,
+        height: u32,
+        empty_hashes: &[Hash],
+        v: &mut Vec<(Side, Hash)>,
+    ) {
         match self {
-            Node::Branch { left, right, .. } => {
-                println!("self: {:?}", self.digest());
-                println!("left: {:?}", left.digest());
-                println!("right: {:?}", right.digest());
-                let mut concat = left.hash_bytes().to_vec();
-                concat.extend_from_slice(right.hash_bytes());
-                let hash = digest(&SHA256, &concat[..]);
-
-                println!(
-                    "left + right == self? {}",
-                    hash.as_ref() == self.digest().as_ref()
-                );
-                println!();
-                match id & 1 {
-                    0 => {
-                        println!("went left");
-                        v.push((Side::Right, right.digest().clone()));
-                        left.get_hashes_for_file(id >> 1, v)
-                    }
-                    1 => {
-                        println!("went right");
-                        v.push((Side::Left, left.digest().clone()));
-                        right.get_hashes_for_file(id >> 1, v)
-                    }
-                    _ => unreachable!(),
+            Node::Branch { left, right, .. } => match (id >> (height - 1)) & 1 {
+                0 => {
+                    v.push((Side::Right, *right.digest()));
+                    left.get_hashes_for_file(id, height - 1, empty_hashes, v)
                 }
-            }
+                1 => {
+                    v.push((Side::Left, *left.digest()));
+                    right.get_hashes_for_file(id, height - 1, empty_hashes, v)
+                }
+                _ => unreachable!(),
+            },
             Node::Leaf { .. } => {}
+            // every sibling below an empty subtree is empty as well
+            Node::Empty { .. } => {
+                for h in (0..height).rev() {
+                    let side = match (id >> h) & 1 {
+                        0 => Side::Right,
+                        _ => Side::Left,
+                    };
+                    v.push((side, empty_hashes[h as usize]));
+                }
+            }
         }
     }
 }
@@ -247,3 +293,172 @@ impl Default for MerkleTree {
         MerkleTree::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use types::merkle::{compute_top_hash, sha256, verify_consistency, verify_update};
+    use types::{ConsistencyProof, FileData, KdfParams, SignatureAlgorithm, TreeUpdate};
+
+    /// A file that is never saved, so building trees needs no disk
+    fn file(n: u8) -> File {
+        let data = FileData {
+            name_nonce: [n; 12],
+            name: vec![n],
+            name_hash: n.to_string(),
+            nonce: [n; 12],
+            contents: vec![n; 4],
+            version: 1,
+            signature: vec![n],
+            signature_algorithm: SignatureAlgorithm::Ed25519,
+            signer: vec![n; 32],
+            kdf: KdfParams {
+                version: 1,
+                mem_cost: 19 * 1024,
+                time_cost: 2,
+                lanes: 1,
+            },
+        };
+        let content_hash = sha256(&data.contents);
+        File::new(data, content_hash, PathBuf::from(n.to_string()))
+    }
+
+    fn leaf(tree: &MerkleTree, id: u64) -> Hash {
+        hash_leaf(tree.get_file(id))
+    }
+
+    /// Puts a file in a leaf, returning the update a client would be shown
+    fn set(tree: &mut MerkleTree, id: u64, data: Option<File>) -> TreeUpdate {
+        let old_leaf = leaf(tree, id);
+        *tree.get_file_mut(id) = data;
+        tree.recompute_hashes();
+        TreeUpdate {
+            old_leaf: old_leaf.to_vec(),
+            new_leaf: leaf(tree, id).to_vec(),
+            tree: tree.get_merkle_data_for_file(id),
+        }
+    }
+
+    fn assert_proof(tree: &mut MerkleTree, id: u64) {
+        let leaf = leaf(tree, id);
+        let proof = tree.get_merkle_data_for_file(id);
+        assert_eq!(proof.top_hash, tree.top_hash().to_vec());
+        assert_eq!(compute_top_hash(&leaf, &proof.hashes), proof.top_hash);
+        assert_eq!(proof.leaf_index(), Some(id));
+        assert_eq!(proof.tree_size(), Some(tree.size()));
+    }
+
+    #[test]
+    fn new_tree_is_a_single_empty_leaf() {
+        let mut tree = MerkleTree::new();
+        assert_eq!(tree.size(), 1);
+        assert_eq!(*tree.top_hash(), empty_leaf_hash());
+        assert_proof(&mut tree, 0);
+        assert!(tree.get_file(0).is_none());
+        assert!(tree.get_file(5).is_none());
+    }
+
+    #[test]
+    fn proofs_while_growing() {
+        let mut tree = MerkleTree::new();
+        for id in 0..9 {
+            set(&mut tree, id, Some(file(id as u8)));
+            assert_eq!(tree.size(), (id + 1).next_power_of_two());
+            for proven in 0..tree.size() {
+                assert_proof(&mut tree, proven);
+            }
+        }
+        assert_eq!(tree.size(), 16);
+        assert_eq!(
+            tree.get_file(3).as_ref().unwrap().leaf_hash(),
+            file(3).leaf_hash()
+        );
+    }
+
+    #[test]
+    fn proofs_under_empty_subtrees() {
+        let mut tree = MerkleTree::new();
+        set(&mut tree, 0, Some(file(0)));
+        // jumps from one leaf to sixteen, leaving most of them in empty subtrees
+        set(&mut tree, 13, Some(file(13)));
+        assert_eq!(tree.size(), 16);
+
+        for id in 0..16 {
+            assert_proof(&mut tree, id);
+        }
+        // proving an empty leaf doesn't expand its subtree
+        assert!(tree.root.get_leaf(6, tree.depth).is_none());
+    }
+
+    #[test]
+    fn incremental_and_full_hashes_agree() {
+        let mut tree = MerkleTree::new();
+        for id in [2, 0, 7, 3] {
+            set(&mut tree, id, Some(file(id as u8)));
+        }
+        set(&mut tree, 7, None);
+        let top = *tree.top_hash();
+        tree.recompute_all_hashes();
+        assert_eq!(*tree.top_hash(), top);
+    }
+
+    #[test]
+    fn updates_verify_against_the_previous_top_hash() {
+        let mut tree = MerkleTree::new();
+        // adding, growing by one and several levels, replacing and deleting
+        let changes = [
+            (0, Some(1)),
+            (1, Some(2)),
+            (2, Some(3)),
+            (9, Some(4)),
+            (1, Some(5)),
+            (0, None),
+        ];
+        for (id, n) in changes {
+            let old_root = tree.top_hash().to_vec();
+            let update = set(&mut tree, id, n.map(file));
+            assert!(verify_update(
+                &old_root,
+                &update.old_leaf,
+                &update.new_leaf,
+                &update.tree.hashes,
+                &update.tree.top_hash
+            ));
+            assert!(!verify_update(
+                &old_root,
+                &update.new_leaf,
+                &update.old_leaf,
+                &update.tree.hashes,
+                &update.tree.top_hash
+            ));
+        }
+    }
+
+    #[test]
+    fn consistency_across_growth() {
+        let mut tree = MerkleTree::new();
+        let start = tree.top_hash().to_vec();
+        let mut updates = vec![set(&mut tree, 0, Some(file(0)))];
+        let middle = (tree.top_hash().to_vec(), tree.size());
+        for (id, n) in [(1, Some(1)), (5, Some(5)), (0, None), (2, Some(2))] {
+            updates.push(set(&mut tree, id, n.map(file)));
+        }
+        let end = Some((tree.top_hash().to_vec(), tree.size()));
+
+        let proof = ConsistencyProof {
+            updates: updates.clone(),
+        };
+        assert_eq!(verify_consistency(&start, 1, &proof), end);
+
+        let proof = ConsistencyProof {
+            updates: updates[1..].to_vec(),
+        };
+        assert_eq!(verify_consistency(&middle.0, middle.1, &proof), end);
+        // skipping an update breaks the chain
+        let proof = ConsistencyProof {
+            updates: updates[2..].to_vec(),
+        };
+        assert_eq!(verify_consistency(&middle.0, middle.1, &proof), None);
+    }
+}
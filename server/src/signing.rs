@@ -0,0 +1,72 @@
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use types::{SignedTreeHead, TreeHead};
+
+use super::data::Files;
+
+/// Name of the file in the save directory holding the server's private key
+static KEY_FILE: &str = "server-key.pk8";
+
+/// The key the server signs tree heads with. It is kept in the save
+/// directory, so clients pinning its public key keep working after a restart.
+pub struct ServerKey {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ServerKey {
+    /// Loads the key from the save directory, generating it on first start
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(KEY_FILE);
+        let pkcs8 = match fs::read(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| io::Error::other("Couldn't generate server key"))?;
+                fs::create_dir_all(dir)?;
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                // readable by the server's user only, where there are
+                // permission bits to say so
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                options.open(&path)?.write_all(pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e),
+        };
+
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid server key"))?;
+        Ok(Self { key_pair })
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key_pair.public_key().as_ref().to_vec()
+    }
+
+    /// Signs the current top hash and size of an account's tree
+    pub fn sign_head(&self, owner: &str, files: &Files) -> SignedTreeHead {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+
+        let head = TreeHead {
+            owner: owner.to_string(),
+            top_hash: files.top_hash().to_vec(),
+            tree_size: files.tree_size(),
+            timestamp,
+        };
+        let signature = self.key_pair.sign(&head.signed_bytes()).as_ref().to_vec();
+
+        SignedTreeHead { head, signature }
+    }
+}
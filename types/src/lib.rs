@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+pub mod merkle;
+
 type Hash = Vec<u8>;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -9,7 +11,11 @@ pub struct FileData {
     pub name_hash: String, // used to look up the file
     pub nonce: [u8; 12],
     pub contents: Vec<u8>,
+    pub version: u64, // increases with every push of the same name
     pub signature: Vec<u8>,
+    pub signature_algorithm: SignatureAlgorithm,
+    pub signer: Hash, // fingerprint of the public key the signature verifies against
+    pub kdf: KdfParams, // how the keys the name and contents are encrypted with were derived
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -21,6 +27,7 @@ pub struct FileInfo {
 pub struct CreateInfo {
     pub name: String,
     pub password: String,
+    pub key_derivation: KeyDerivation,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -29,9 +36,53 @@ pub struct LoginInfo {
     pub password: String,
 }
 
+/// Returned by a successful login or account creation. The token has to be
+/// sent as a bearer token on every request that touches files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+    pub key_derivation: KeyDerivation,
+}
+
+/// Argon2id cost parameters and the version of how subkeys are derived from
+/// the resulting key, recorded with everything encrypted by a key derived
+/// with them so either can change without losing old files
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct KdfParams {
+    pub version: u32,
+    pub mem_cost: u32, // KiB
+    pub time_cost: u32,
+    pub lanes: u32,
+}
+
+/// Algorithm a file was signed with, so files signed with different kinds
+/// of keys can be kept side by side
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    RsaPkcs1Sha256,
+    Ed25519,
+    EcdsaP256Sha256,
+}
+
+/// A public key the owner of an account signs files with
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PublicKeyInfo {
+    pub algorithm: SignatureAlgorithm,
+    pub public_key: Vec<u8>,
+}
+
+/// What an account's encryption key is derived from besides the password.
+/// Chosen by the client when the account is created and kept by the server,
+/// so every device derives the same key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyDerivation {
+    pub salt: Vec<u8>,
+    pub params: KdfParams,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FileList {
-    pub top_hash: Hash,
+    pub head: SignedTreeHead,
     pub list: Vec<FileListEntry>,
 }
 
@@ -42,15 +93,72 @@ pub struct FileListEntry {
     pub name: Vec<u8>,
     pub name_nonce: [u8; 12],
     pub size: usize,
+    pub version: u64,
+    pub signature: Vec<u8>,
+    pub signature_algorithm: SignatureAlgorithm,
+    pub signer: Hash,
+    pub content_hash: Hash, // hash of the encrypted contents, part of the merkle leaf
+    pub kdf: KdfParams,
 }
 
-/// All the neighboring hashes required to compute a new top hash.
+/// All the neighboring hashes required to compute a new top hash, ordered
+/// from the leaf up to the root.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MerkleData {
     pub top_hash: Hash,
     pub hashes: Vec<(Side, Hash)>,
 }
 
+/// What the server claims an account's tree looked like at a point in time
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TreeHead {
+    pub owner: String,
+    pub top_hash: Hash,
+    pub tree_size: u64,
+    pub timestamp: u64, // seconds since the unix epoch
+}
+
+/// A tree head signed with the server's Ed25519 key. Since the server can't
+/// deny having signed it, a signed head that can't be reconciled with another
+/// one is proof that the server misbehaved.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedTreeHead {
+    pub head: TreeHead,
+    pub signature: Vec<u8>,
+}
+
+/// Asks for the changes made to a tree since it had the given top hash and
+/// number of leaves
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsistencyInfo {
+    pub top_hash: Hash,
+    pub tree_size: u64,
+}
+
+/// A single leaf changing from `old_leaf` to `new_leaf`, where `tree` proves
+/// the new leaf against the top hash after the change
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TreeUpdate {
+    pub old_leaf: Hash,
+    pub new_leaf: Hash,
+    pub tree: MerkleData,
+}
+
+/// Every update made to a tree since the requested top hash, in order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub updates: Vec<TreeUpdate>,
+}
+
+/// Where a pushed file ended up, along with the proof that it is part of
+/// the tree with the new top hash
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PushResult {
+    pub leaf_index: u64,
+    pub tree: MerkleData,
+    pub head: SignedTreeHead,
+}
+
 /// The side to append the given hash to
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Side {
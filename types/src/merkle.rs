@@ -0,0 +1,548 @@
+//! Hashing shared by the server's merkle tree and the client's proof checks.
+//!
+//! Leaves and branches are hashed with different prefixes (as in RFC 6962),
+//! so the hash of a branch can never be passed off as the hash of a leaf.
+
+use ring::digest::{digest, SHA256};
+
+use crate::{
+    ConsistencyProof, FileData, FileListEntry, KdfParams, MerkleData, PublicKeyInfo, Side,
+    SignatureAlgorithm, TreeHead,
+};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest(&SHA256, data).as_ref());
+    hash
+}
+
+/// Hash of a leaf without a file
+pub fn empty_leaf_hash() -> [u8; 32] {
+    sha256(&[LEAF_PREFIX])
+}
+
+/// Hash of a leaf holding a file. Every field is length prefixed, so no two
+/// different files can produce the same input.
+#[allow(clippy::too_many_arguments)]
+pub fn leaf_hash(
+    name_hash: &str,
+    name_nonce: &[u8; 12],
+    name: &[u8],
+    nonce: &[u8; 12],
+    version: u64,
+    signature: &[u8],
+    signature_algorithm: SignatureAlgorithm,
+    signer: &[u8],
+    content_hash: &[u8],
+    kdf: &KdfParams,
+) -> [u8; 32] {
+    let mut data = vec![LEAF_PREFIX];
+    for field in [
+        name_hash.as_bytes(),
+        name_nonce,
+        name,
+        nonce,
+        &version.to_be_bytes(),
+        signature,
+        &[signature_algorithm.id()],
+        signer,
+        content_hash,
+        &kdf.to_bytes(),
+    ] {
+        data.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        data.extend_from_slice(field);
+    }
+    sha256(&data)
+}
+
+pub fn node_hash(left: &[u8], right: &[u8]) -> [u8; 32] {
+    let mut data = Vec::with_capacity(1 + left.len() + right.len());
+    data.push(NODE_PREFIX);
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    sha256(&data)
+}
+
+/// Walks a merkle proof from the leaf hash up, returning the resulting top hash
+pub fn compute_top_hash(leaf_hash: &[u8], hashes: &[(Side, Vec<u8>)]) -> Vec<u8> {
+    let mut hash = leaf_hash.to_vec();
+
+    for (side, tree_hash) in hashes {
+        hash = match side {
+            Side::Left => node_hash(tree_hash, &hash),
+            Side::Right => node_hash(&hash, tree_hash),
+        }
+        .to_vec();
+    }
+
+    hash
+}
+
+impl FileData {
+    pub fn leaf_hash(&self) -> [u8; 32] {
+        leaf_hash(
+            &self.name_hash,
+            &self.name_nonce,
+            &self.name,
+            &self.nonce,
+            self.version,
+            &self.signature,
+            self.signature_algorithm,
+            &self.signer,
+            &sha256(&self.contents),
+            &self.kdf,
+        )
+    }
+}
+
+impl MerkleData {
+    /// Number of leaves in the tree the proof was made for, or `None` if the
+    /// proof is too deep for the size to fit in a `u64`
+    pub fn tree_size(&self) -> Option<u64> {
+        let depth = u32::try_from(self.hashes.len()).ok()?;
+        1u64.checked_shl(depth)
+    }
+
+    /// Index of the leaf the proof is for, every sibling on the left means
+    /// the path went right at that height. `None` if the proof is too deep
+    /// for the index to fit in a `u64`.
+    pub fn leaf_index(&self) -> Option<u64> {
+        self.tree_size()?;
+        let index = self
+            .hashes
+            .iter()
+            .enumerate()
+            .filter(|(_, (side, _))| *side == Side::Left)
+            .fold(0, |index, (height, _)| index | 1 << height);
+        Some(index)
+    }
+}
+
+impl TreeHead {
+    /// The bytes the server signs, every variable length field is length
+    /// prefixed so no two heads produce the same input
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut data = b"krypto tree head v1".to_vec();
+        for field in [self.owner.as_bytes(), &self.top_hash] {
+            data.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            data.extend_from_slice(field);
+        }
+        data.extend_from_slice(&self.tree_size.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data
+    }
+}
+
+impl FileListEntry {
+    pub fn leaf_hash(&self) -> [u8; 32] {
+        leaf_hash(
+            &self.name_hash,
+            &self.name_nonce,
+            &self.name,
+            &self.nonce,
+            self.version,
+            &self.signature,
+            self.signature_algorithm,
+            &self.signer,
+            &self.content_hash,
+            &self.kdf,
+        )
+    }
+}
+
+impl SignatureAlgorithm {
+    /// Identifier committed to by the merkle leaf, never reused
+    pub fn id(&self) -> u8 {
+        match self {
+            SignatureAlgorithm::RsaPkcs1Sha256 => 1,
+            SignatureAlgorithm::Ed25519 => 2,
+            SignatureAlgorithm::EcdsaP256Sha256 => 3,
+        }
+    }
+}
+
+impl PublicKeyInfo {
+    /// SHA256 of the public key, which files record to name the key they
+    /// were signed with
+    pub fn fingerprint(&self) -> [u8; 32] {
+        sha256(&self.public_key)
+    }
+}
+
+impl KdfParams {
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..4].copy_from_slice(&self.version.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.mem_cost.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.time_cost.to_be_bytes());
+        bytes[12..].copy_from_slice(&self.lanes.to_be_bytes());
+        bytes
+    }
+}
+
+/// Hash of a subtree of the given height without any files
+pub fn empty_subtree_hash(height: usize) -> [u8; 32] {
+    let mut hash = empty_leaf_hash();
+    for _ in 0..height {
+        hash = node_hash(&hash, &hash);
+    }
+    hash
+}
+
+/// Checks that `new_root` is `old_root` with only the leaf proven by `hashes`
+/// changed from `old_leaf` to `new_leaf`. If the leaf was empty the tree may
+/// have grown to make room for it, which keeps the old tree as its leftmost
+/// subtree.
+pub fn verify_update(
+    old_root: &[u8],
+    old_leaf: &[u8],
+    new_leaf: &[u8],
+    hashes: &[(Side, Vec<u8>)],
+    new_root: &[u8],
+) -> bool {
+    if compute_top_hash(new_leaf, hashes) != new_root {
+        return false;
+    }
+
+    let before = compute_top_hash(old_leaf, hashes);
+    if before == old_root {
+        return true;
+    }
+    if old_leaf != empty_leaf_hash() {
+        return false;
+    }
+
+    // the old tree might have had any depth below the current one
+    (0..hashes.len()).any(|depth| {
+        let mut grown = old_root.to_vec();
+        for height in depth..hashes.len() {
+            grown = node_hash(&grown, &empty_subtree_hash(height)).to_vec();
+        }
+        grown == before
+    })
+}
+
+/// Replays every update in a consistency proof on top of a tree, returning
+/// the top hash and size of the tree they lead to, or `None` if any of them
+/// changed more than its own leaf
+pub fn verify_consistency(
+    old_root: &[u8],
+    old_size: u64,
+    proof: &ConsistencyProof,
+) -> Option<(Vec<u8>, u64)> {
+    let mut root = old_root.to_vec();
+    let mut size = old_size;
+
+    for update in &proof.updates {
+        let tree = &update.tree;
+        if !verify_update(
+            &root,
+            &update.old_leaf,
+            &update.new_leaf,
+            &tree.hashes,
+            &tree.top_hash,
+        ) {
+            return None;
+        }
+        // trees only ever grow
+        let tree_size = tree.tree_size()?;
+        if tree_size < size {
+            return None;
+        }
+        root = tree.top_hash.clone();
+        size = tree_size;
+    }
+
+    Some((root, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TreeUpdate;
+
+    /// Top hash of a full tree over the given leaves
+    fn root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        let (left, right) = leaves.split_at(leaves.len() / 2);
+        node_hash(&root(left), &root(right))
+    }
+
+    /// Proof for a leaf of a full tree, ordered from the leaf up
+    fn proof(leaves: &[[u8; 32]], index: usize) -> MerkleData {
+        let mut hashes = Vec::new();
+        let mut level = leaves.to_vec();
+        let mut index = index;
+        while level.len() > 1 {
+            let sibling = level[index ^ 1].to_vec();
+            hashes.push(match index & 1 {
+                0 => (Side::Right, sibling),
+                _ => (Side::Left, sibling),
+            });
+            level = level.chunks(2).map(|x| node_hash(&x[0], &x[1])).collect();
+            index /= 2;
+        }
+        MerkleData {
+            top_hash: level[0].to_vec(),
+            hashes,
+        }
+    }
+
+    fn leaf(n: u8) -> [u8; 32] {
+        sha256(&[LEAF_PREFIX, n])
+    }
+
+    fn update(leaves: &[[u8; 32]], old_leaf: [u8; 32], index: usize) -> TreeUpdate {
+        TreeUpdate {
+            old_leaf: old_leaf.to_vec(),
+            new_leaf: leaves[index].to_vec(),
+            tree: proof(leaves, index),
+        }
+    }
+
+    #[test]
+    fn proofs_reach_the_top_hash() {
+        let leaves = (0..8).map(leaf).collect::<Vec<_>>();
+        for index in 0..leaves.len() {
+            let tree = proof(&leaves, index);
+            assert_eq!(
+                compute_top_hash(&leaves[index], &tree.hashes),
+                tree.top_hash
+            );
+            assert_eq!(tree.top_hash, root(&leaves));
+            assert_eq!(tree.leaf_index(), Some(index as u64));
+            assert_eq!(tree.tree_size(), Some(8));
+        }
+    }
+
+    #[test]
+    fn single_leaf_tree() {
+        let tree = proof(&[leaf(0)], 0);
+        assert_eq!(tree.top_hash, leaf(0));
+        assert_eq!(tree.leaf_index(), Some(0));
+        assert_eq!(tree.tree_size(), Some(1));
+    }
+
+    #[test]
+    fn too_deep_proofs_have_no_size() {
+        let proof = |depth| MerkleData {
+            top_hash: vec![0; 32],
+            hashes: vec![(Side::Left, vec![0; 32]); depth],
+        };
+        assert_eq!(proof(63).tree_size(), Some(1 << 63));
+        assert_eq!(proof(63).leaf_index(), Some(u64::MAX >> 1));
+        assert_eq!(proof(64).tree_size(), None);
+        assert_eq!(proof(64).leaf_index(), None);
+        assert_eq!(proof(200).tree_size(), None);
+    }
+
+    #[test]
+    fn empty_subtree_hashes() {
+        let empty = [empty_leaf_hash(); 4];
+        assert_eq!(empty_subtree_hash(0), empty_leaf_hash());
+        assert_eq!(empty_subtree_hash(2), root(&empty));
+    }
+
+    #[test]
+    fn update_of_a_single_leaf() {
+        let mut leaves = (0..4).map(leaf).collect::<Vec<_>>();
+        let old_root = root(&leaves);
+        leaves[2] = leaf(9);
+        let tree = proof(&leaves, 2);
+
+        assert!(verify_update(
+            &old_root,
+            &leaf(2),
+            &leaf(9),
+            &tree.hashes,
+            &tree.top_hash
+        ));
+        // the proof doesn't lead to the claimed root
+        assert!(!verify_update(
+            &old_root,
+            &leaf(2),
+            &leaf(9),
+            &tree.hashes,
+            &old_root
+        ));
+        // the old leaf wasn't what the update claims
+        assert!(!verify_update(
+            &old_root,
+            &leaf(3),
+            &leaf(9),
+            &tree.hashes,
+            &tree.top_hash
+        ));
+    }
+
+    #[test]
+    fn update_changing_another_leaf_is_rejected() {
+        let mut leaves = (0..4).map(leaf).collect::<Vec<_>>();
+        let old_root = root(&leaves);
+        leaves[2] = leaf(9);
+        leaves[0] = leaf(8);
+        let tree = proof(&leaves, 2);
+
+        assert!(!verify_update(
+            &old_root,
+            &leaf(2),
+            &leaf(9),
+            &tree.hashes,
+            &tree.top_hash
+        ));
+    }
+
+    #[test]
+    fn update_growing_the_tree() {
+        let old = vec![leaf(0), leaf(1)];
+        let old_root = root(&old);
+
+        // two leaves grown to eight, with the new file in leaf 5
+        let mut leaves = old.clone();
+        leaves.resize(8, empty_leaf_hash());
+        leaves[5] = leaf(5);
+        let tree = proof(&leaves, 5);
+
+        assert!(verify_update(
+            &old_root,
+            &empty_leaf_hash(),
+            &leaf(5),
+            &tree.hashes,
+            &tree.top_hash
+        ));
+        // only an empty leaf can be added by growing
+        assert!(!verify_update(
+            &old_root,
+            &leaf(4),
+            &leaf(5),
+            &tree.hashes,
+            &tree.top_hash
+        ));
+
+        // the old tree has to stay the leftmost subtree
+        let mut moved = vec![empty_leaf_hash(); 8];
+        moved[2] = leaf(0);
+        moved[3] = leaf(1);
+        moved[5] = leaf(5);
+        let tree = proof(&moved, 5);
+        assert!(!verify_update(
+            &old_root,
+            &empty_leaf_hash(),
+            &leaf(5),
+            &tree.hashes,
+            &tree.top_hash
+        ));
+    }
+
+    #[test]
+    fn consistency_over_several_updates() {
+        let mut leaves = vec![empty_leaf_hash()];
+        let start = root(&leaves);
+        let mut updates = Vec::new();
+
+        leaves[0] = leaf(0);
+        updates.push(update(&leaves, empty_leaf_hash(), 0));
+        leaves.push(empty_leaf_hash());
+        leaves[1] = leaf(1);
+        updates.push(update(&leaves, empty_leaf_hash(), 1));
+        leaves.resize(4, empty_leaf_hash());
+        leaves[2] = leaf(2);
+        updates.push(update(&leaves, empty_leaf_hash(), 2));
+        leaves[0] = empty_leaf_hash();
+        updates.push(update(&leaves, leaf(0), 0));
+
+        let proof = ConsistencyProof { updates };
+        assert_eq!(
+            verify_consistency(&start, 1, &proof),
+            Some((root(&leaves).to_vec(), 4))
+        );
+        // a top hash the updates don't start from
+        assert_eq!(verify_consistency(&leaf(7), 1, &proof), None);
+        // a tree can't shrink
+        assert_eq!(verify_consistency(&start, 8, &proof), None);
+    }
+
+    #[test]
+    fn consistency_rejects_a_rewritten_leaf() {
+        let old = vec![leaf(0), leaf(1)];
+        let leaves = vec![leaf(7), leaf(9)];
+        let proof = ConsistencyProof {
+            updates: vec![update(&leaves, leaf(1), 1)],
+        };
+        assert_eq!(verify_consistency(&root(&old), 2, &proof), None);
+
+        let proof = ConsistencyProof { updates: vec![] };
+        assert_eq!(
+            verify_consistency(&root(&old), 2, &proof),
+            Some((root(&old).to_vec(), 2))
+        );
+    }
+
+    fn file() -> FileData {
+        FileData {
+            name_nonce: [1; 12],
+            name: vec![2; 8],
+            name_hash: String::from("name hash"),
+            nonce: [3; 12],
+            contents: vec![4; 16],
+            version: 1,
+            signature: vec![5; 64],
+            signature_algorithm: SignatureAlgorithm::Ed25519,
+            signer: vec![6; 32],
+            kdf: KdfParams {
+                version: 1,
+                mem_cost: 19 * 1024,
+                time_cost: 2,
+                lanes: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn nodes_and_leaves_hash_differently() {
+        let (left, right) = (leaf(1), leaf(2));
+        let mut as_leaf = vec![LEAF_PREFIX];
+        as_leaf.extend_from_slice(&left);
+        as_leaf.extend_from_slice(&right);
+        assert_ne!(node_hash(&left, &right), sha256(&as_leaf));
+
+        let mut unprefixed = left.to_vec();
+        unprefixed.extend_from_slice(&right);
+        assert_ne!(node_hash(&left, &right), sha256(&unprefixed));
+        assert_ne!(empty_leaf_hash(), sha256(&[]));
+    }
+
+    #[test]
+    fn leaves_commit_to_every_field() {
+        let changes: [fn(&mut FileData); 10] = [
+            |x| x.name_nonce[0] ^= 1,
+            |x| x.name.push(0),
+            |x| x.name_hash.push('x'),
+            |x| x.nonce[0] ^= 1,
+            |x| x.contents[0] ^= 1,
+            |x| x.version += 1,
+            |x| x.signature[0] ^= 1,
+            |x| x.signature_algorithm = SignatureAlgorithm::EcdsaP256Sha256,
+            |x| x.signer[0] ^= 1,
+            |x| x.kdf.time_cost += 1,
+        ];
+        let original = file().leaf_hash();
+        for change in changes {
+            let mut changed = file();
+            change(&mut changed);
+            assert_ne!(changed.leaf_hash(), original);
+        }
+    }
+
+    #[test]
+    fn moving_bytes_between_leaf_fields_changes_the_hash() {
+        let mut moved = file();
+        moved.name.push(moved.signature.remove(0));
+        assert_ne!(moved.leaf_hash(), file().leaf_hash());
+    }
+}